@@ -0,0 +1,113 @@
+//! Compares the serde based CSV decoder with the fast CSV decoder, reading or
+//! memory-mapping a large generated transaction file. The number of rows defaults to 10 million and can
+//! be set with the `PAYENG_BENCH_ROWS` environment variable.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use payeng::format::{CsvDecoder, CsvDialect, Decoder, FastCsvDecoder};
+use payeng::prelude::runtime;
+
+/// Allocator counting the allocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const ROWS: u64 = 10_000_000;
+
+/// Writes `rows` transactions of 10000 clients, with a dispute every 100 rows.
+fn write_input(path: &Path, rows: u64) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    writeln!(writer, "type, client, tx, amount").unwrap();
+    for tx in 1..=rows {
+        let client = tx % 10_000;
+        match tx % 100 {
+            0 => writeln!(writer, "dispute, {client}, {},", tx - 100),
+            1..=70 => writeln!(
+                writer,
+                "deposit, {client}, {tx}, {}.{:04}",
+                tx % 500,
+                tx % 9999
+            ),
+            _ => writeln!(writer, "withdrawal, {client}, {tx}, 1.5"),
+        }
+        .unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// Decodes every record and returns the number of decoded transactions.
+fn decode_all(mut decoder: impl Decoder) -> u64 {
+    let mut decoded = 0;
+    while let Some(result) = decoder.decode() {
+        decoded += u64::from(result.is_ok());
+    }
+    decoded
+}
+
+/// Returns the number of allocations made while running the closure.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn csv(c: &mut Criterion) {
+    let rows = std::env::var("PAYENG_BENCH_ROWS")
+        .ok()
+        .and_then(|rows| rows.parse().ok())
+        .unwrap_or(ROWS);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv");
+    write_input(&path, rows);
+
+    let serde = allocations(|| {
+        decode_all(CsvDecoder::new(File::open(&path).unwrap()));
+    });
+    let fast = allocations(|| {
+        decode_all(FastCsvDecoder::new(File::open(&path).unwrap()));
+    });
+    println!(
+        "allocations for {rows} rows: serde {serde} ({:.2} per row), fast {fast} ({:.4} per row)",
+        serde as f64 / rows as f64,
+        fast as f64 / rows as f64,
+    );
+
+    let mut group = c.benchmark_group("csv_decode");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(rows));
+    group.bench_function(BenchmarkId::new("serde", rows), |b| {
+        b.iter(|| decode_all(CsvDecoder::new(File::open(&path).unwrap())))
+    });
+    group.bench_function(BenchmarkId::new("fast", rows), |b| {
+        b.iter(|| decode_all(FastCsvDecoder::new(File::open(&path).unwrap())))
+    });
+    group.bench_function(BenchmarkId::new("mapped", rows), |b| {
+        b.iter(|| {
+            let mapped = runtime::new_mapped_reader(&path).unwrap().unwrap();
+            decode_all(FastCsvDecoder::from_buffered(mapped, CsvDialect::default()))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, csv);
+criterion_main!(benches);
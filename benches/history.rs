@@ -0,0 +1,139 @@
+//! Compares the throughput and the memory use of the per-account transaction
+//! history with the compact and the disk-backed history indexes.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use parking_lot::Mutex;
+use payeng::prelude::{
+    runtime, Client, CompactHistory, DiskHistory, DisputeState, HistoryEntry, HistoryIndex,
+    SharedHistory, TransactionId,
+};
+use rust_decimal::Decimal;
+
+/// Allocator tracking the peak of allocated bytes.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const TRANSACTIONS: u32 = 200_000;
+const CACHE: usize = 10_000;
+
+/// Returns deposits spread over 1000 clients, disputing every 100th deposit.
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=TRANSACTIONS {
+        let client = tx % 1000;
+        writeln!(input, "deposit,{client},{tx},1.5").unwrap();
+        if tx % 100 == 0 {
+            let disputed = tx / 2;
+            writeln!(input, "dispute,{},{disputed},", disputed % 1000).unwrap();
+        }
+    }
+    input
+}
+
+fn run(input: &str, history: Option<SharedHistory>) {
+    let config = runtime::Config {
+        history,
+        ..runtime::Config::new(10_000)
+    };
+    runtime::run_with(
+        std::io::Cursor::new(input.to_owned()),
+        std::io::sink(),
+        config,
+    )
+    .unwrap();
+}
+
+fn disk_history(dir: &tempfile::TempDir) -> SharedHistory {
+    let path = dir.path().join("history.bin");
+    let _ = std::fs::remove_file(&path);
+    Arc::new(Mutex::new(DiskHistory::open(path, CACHE).unwrap()))
+}
+
+/// Returns the peak of bytes allocated while running the closure.
+fn peak_memory(f: impl FnOnce()) -> usize {
+    let base = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - base
+}
+
+fn compact_history() -> SharedHistory {
+    Arc::new(Mutex::new(CompactHistory::new()))
+}
+
+/// Returns the bytes used per transaction by an index holding `TRANSACTIONS` entries.
+fn bytes_per_transaction(mut index: impl HistoryIndex) -> usize {
+    let entry = HistoryEntry {
+        client: Client::from(1),
+        amount: Decimal::new(15, 1),
+        state: DisputeState::Undisputed,
+    };
+    let used = peak_memory(|| {
+        for tx in 1..=TRANSACTIONS {
+            index
+                .insert(TransactionId::from(tx), entry.clone())
+                .unwrap();
+        }
+    });
+    used / TRANSACTIONS as usize
+}
+
+fn history(c: &mut Criterion) {
+    let input = input();
+    let dir = tempfile::tempdir().unwrap();
+
+    let memory = peak_memory(|| run(&input, None));
+    let compact = peak_memory(|| run(&input, Some(compact_history())));
+    let disk = peak_memory(|| run(&input, Some(disk_history(&dir))));
+    println!(
+        "peak memory for {TRANSACTIONS} transactions: per-account {} KiB, compact {} KiB, disk {} KiB",
+        memory / 1024,
+        compact / 1024,
+        disk / 1024
+    );
+    println!(
+        "index bytes per transaction: compact {}, disk {}",
+        bytes_per_transaction(CompactHistory::new()),
+        bytes_per_transaction(DiskHistory::open(dir.path().join("entries.bin"), CACHE).unwrap()),
+    );
+
+    let mut group = c.benchmark_group("history");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(TRANSACTIONS.into()));
+    group.bench_function(BenchmarkId::new("memory", TRANSACTIONS), |b| {
+        b.iter(|| run(&input, None))
+    });
+    group.bench_function(BenchmarkId::new("compact", TRANSACTIONS), |b| {
+        b.iter(|| run(&input, Some(compact_history())))
+    });
+    group.bench_function(BenchmarkId::new("disk", TRANSACTIONS), |b| {
+        b.iter(|| run(&input, Some(disk_history(&dir))))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, history);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Measures the tracing overhead of the pipeline with the filter turned off and
+//! at the `info` level, which enables the per-transaction spans. Run it with and
+//! without `--no-default-features` to compare with the spans compiled out.
+
+use std::fmt::Write;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use payeng::prelude::runtime;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+const TRANSACTIONS: u32 = 200_000;
+
+/// Returns blocks of seven deposits and three withdrawals spread over 1000
+/// clients, disputing a deposit every 100 transactions.
+fn input() -> String {
+    let mut input = String::from("type,client,tx,amount\n");
+    for tx in 1..=TRANSACTIONS {
+        let client = tx / 10 % 1000;
+        match tx % 10 {
+            0..=6 => writeln!(input, "deposit,{client},{tx},1.5").unwrap(),
+            _ => writeln!(input, "withdrawal,{client},{tx},1.0").unwrap(),
+        }
+        if tx % 100 == 0 {
+            let disputed = tx - 5;
+            writeln!(input, "dispute,{},{disputed},", disputed / 10 % 1000).unwrap();
+        }
+    }
+    input
+}
+
+fn run(input: &str) {
+    runtime::run_with(
+        std::io::Cursor::new(input.to_owned()),
+        std::io::sink(),
+        runtime::Config::new(10_000),
+    )
+    .unwrap();
+}
+
+fn tracing(c: &mut Criterion) {
+    let input = input();
+    let (filter, handle) = reload::Layer::new(EnvFilter::new("off"));
+    let subscriber = Registry::default()
+        .with(filter)
+        .with(fmt::layer().compact().with_writer(std::io::sink));
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let spans = if cfg!(feature = "transaction-spans") {
+        "spans"
+    } else {
+        "no-spans"
+    };
+    let mut group = c.benchmark_group(format!("tracing/{spans}"));
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(TRANSACTIONS.into()));
+    for directives in ["off", "info"] {
+        handle.reload(EnvFilter::new(directives)).unwrap();
+        group.bench_function(BenchmarkId::new(directives, TRANSACTIONS), |b| {
+            b.iter(|| run(&input))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, tracing);
+criterion_main!(benches);
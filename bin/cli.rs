@@ -0,0 +1,390 @@
+//! Command line arguments.
+
+use std::io::{self, IsTerminal};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use parking_lot::Mutex;
+use payeng::compression::Compression;
+use payeng::format::{CsvDialect, Format, COLUMNS};
+use payeng::idempotency::IdempotencyStore;
+use payeng::metrics::Metrics;
+use payeng::prelude::runtime::{self, Config, Listeners, STDIO_PATH};
+use payeng::prelude::{
+    AccountStore, Client, CompactHistory, DiskHistory, DisputeWindow, EventSink, JsonLinesSink,
+    LogStore, ReportMode, SharedHistory,
+};
+use payeng::snapshot::{SnapshotPolicy, Snapshots};
+use payeng::telemetry::{LogFormat, Rotation, Tracer, CLIENT_TRACE_TARGET};
+use payeng::transport::DEFAULT_BATCH_SIZE;
+
+const CAPACITY: usize = 10_000;
+const IDEMPOTENCY_CAPACITY: usize = 100_000;
+const STORE_CACHE: usize = 10_000;
+const HISTORY_CACHE: usize = 1_000_000;
+
+/// Process a transaction file and print the account report.
+#[derive(Debug, Parser)]
+#[command(name = "payeng", version)]
+pub struct Args {
+    /// Transaction input file, `-` reads from stdin. Gzip and zstd inputs are
+    /// decompressed transparently.
+    #[arg(required_unless_present_any = ["listen", "http"])]
+    pub input: Option<PathBuf>,
+
+    /// Run as a long-running server accepting newline delimited transactions
+    /// over TCP on this address instead of processing an input file. The server
+    /// stops and writes the report on SIGINT or SIGTERM.
+    #[arg(long, value_name = "ADDR", conflicts_with = "input")]
+    pub listen: Option<SocketAddr>,
+
+    /// Run as a long-running server exposing the HTTP API on this address.
+    /// Transaction lookups need `--history` or `--compact-history`.
+    #[arg(long, value_name = "ADDR", conflicts_with = "input")]
+    pub http: Option<SocketAddr>,
+
+    /// Expose Prometheus metrics on `GET /metrics` at this address in server mode.
+    #[arg(long, value_name = "ADDR", conflicts_with = "input")]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Account report output file, `-` writes to stdout.
+    #[arg(short, long, default_value = STDIO_PATH)]
+    pub output: PathBuf,
+
+    /// Rejected transactions report output file, written in the output format.
+    #[arg(long)]
+    pub rejections: Option<PathBuf>,
+
+    /// Account log file backing the account store. Accounts beyond the cache
+    /// are kept on disk, and the accounts are restored from the log on restart.
+    #[arg(long, value_name = "PATH")]
+    pub store: Option<PathBuf>,
+
+    /// Maximum number of accounts kept in memory by the on-disk account store.
+    #[arg(long, value_name = "N", default_value_t = STORE_CACHE, requires = "store")]
+    pub store_cache: usize,
+
+    /// File backing the transaction history index, for inputs whose transaction
+    /// history does not fit in memory.
+    #[arg(long, value_name = "PATH")]
+    pub history: Option<PathBuf>,
+
+    /// Maximum number of transaction history entries cached in memory.
+    #[arg(long, value_name = "N", default_value_t = HISTORY_CACHE, requires = "history")]
+    pub history_cache: usize,
+
+    /// Keep the transaction history in a compact table shared by the accounts,
+    /// reducing the memory used per transaction.
+    #[arg(long, conflicts_with = "history")]
+    pub compact_history: bool,
+
+    /// Transactions remaining disputable: `unbounded`, the N most recent
+    /// transactions of each account (`account:N`) or the N most recent
+    /// transactions of the input (`global:N`).
+    #[arg(long, value_name = "WINDOW", default_value = "unbounded")]
+    pub dispute_window: DisputeWindow,
+
+    /// Account events output file, written as JSON lines.
+    #[arg(long, value_name = "PATH")]
+    pub events: Option<PathBuf>,
+
+    /// File persisting the idempotency keys of the submitted transactions, so that
+    /// resubmitted transactions are not applied twice, including across restarts.
+    #[arg(long, value_name = "PATH")]
+    pub idempotency_store: Option<PathBuf>,
+
+    /// Maximum number of idempotency keys remembered.
+    #[arg(long, default_value_t = IDEMPOTENCY_CAPACITY)]
+    pub idempotency_capacity: usize,
+
+    /// Directory receiving periodic account snapshots in the output format. A
+    /// snapshot is also written when the process receives `SIGUSR1`.
+    #[arg(long, value_name = "DIR")]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Write a snapshot every N processed transactions.
+    #[arg(long, value_name = "N", requires = "snapshot_dir")]
+    pub snapshot_every: Option<u64>,
+
+    /// Write a snapshot every SECS seconds.
+    #[arg(long, value_name = "SECS", requires = "snapshot_dir")]
+    pub snapshot_interval: Option<u64>,
+
+    /// Number of snapshot files kept, the oldest are removed first.
+    #[arg(long, value_name = "N", requires = "snapshot_dir")]
+    pub snapshot_keep: Option<usize>,
+
+    /// Only report the accounts changed since the previous report or snapshot.
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Output compression (none, gzip or zstd), detected from the output
+    /// extension by default.
+    #[arg(long)]
+    pub compression: Option<Compression>,
+
+    /// Transaction input format (csv or jsonl).
+    #[arg(long, default_value = "csv")]
+    pub input_format: Format,
+
+    /// CSV input field delimiter, a single ASCII character or `tab`.
+    #[arg(long, default_value = ",", value_parser = parse_byte)]
+    pub delimiter: u8,
+
+    /// CSV input quote character.
+    #[arg(long, default_value = "\"", value_parser = parse_byte)]
+    pub quote: u8,
+
+    /// The CSV input has no header record, columns are read in `--columns` order.
+    #[arg(long)]
+    pub no_headers: bool,
+
+    /// Column order of a CSV input without header record.
+    #[arg(long, value_delimiter = ',', default_values = COLUMNS, value_parser = parse_column)]
+    pub columns: Vec<String>,
+
+    /// CSV header alias in the `alias=column` form, e.g. `tx_id=tx`. Can be repeated.
+    #[arg(long = "alias", value_name = "ALIAS=COLUMN", value_parser = parse_alias)]
+    pub aliases: Vec<(String, String)>,
+
+    /// Reject CSV records whose number of fields differs from the header.
+    #[arg(long)]
+    pub strict_columns: bool,
+
+    /// Every CSV record fits on one line: quoted fields contain no line break.
+    /// Lets `--parse-threads` split the input without scanning it first.
+    #[arg(long)]
+    pub single_line_records: bool,
+
+    /// Abort on the first malformed record without writing a partial report.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Account report format (csv, jsonl or, with the `arrow` feature, arrow).
+    #[arg(long, default_value = "csv")]
+    pub output_format: Format,
+
+    /// Capacity of the transaction channel.
+    #[arg(long, default_value_t = CAPACITY)]
+    pub capacity: usize,
+
+    /// Maximum number of transactions moved through the channel at once.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+
+    /// Number of threads parsing an uncompressed CSV input file.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub parse_threads: usize,
+
+    /// Memory-map the input file instead of reading it. Standard input and
+    /// compressed files are still streamed.
+    #[arg(long)]
+    pub mmap: bool,
+
+    /// Pipeline metrics output file, written as JSON at the end of the run.
+    #[arg(long, value_name = "PATH")]
+    pub metrics: Option<PathBuf>,
+
+    /// Log filter directives, e.g. `info` or `payeng=debug,tiny_http=warn`.
+    /// Defaults to `RUST_LOG`. Nothing is logged when neither is set, unless
+    /// a log file or an OTLP endpoint is given, which logs at the `info` level.
+    #[arg(long, value_name = "DIRECTIVES")]
+    pub log_filter: Option<String>,
+
+    /// Log format (json, pretty or compact).
+    #[arg(long, value_name = "FORMAT", default_value = "json")]
+    pub log_format: LogFormat,
+
+    /// Log file, written instead of stderr.
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Log file rotation (never, hourly or daily).
+    #[arg(long, default_value = "never", requires = "log_file")]
+    pub log_rotation: Rotation,
+
+    /// OTLP/HTTP endpoint receiving the spans, e.g.
+    /// `http://localhost:4318/v1/traces`. Spans are exported at the `info` level
+    /// unless a log filter is given.
+    #[cfg(feature = "otel")]
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Log every operation of this client with its balances before and after.
+    /// Other logs stay quiet unless a log filter is given. Can be repeated.
+    #[arg(long, value_name = "CLIENT")]
+    pub trace_client: Vec<u16>,
+}
+
+impl Args {
+    /// Returns the listeners of the server mode, if any is configured.
+    pub fn listeners(&self) -> io::Result<Option<Listeners>> {
+        if self.listen.is_none() && self.http.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Listeners {
+            tcp: self.listen.map(TcpListener::bind).transpose()?,
+            http: self.http.map(TcpListener::bind).transpose()?,
+            metrics: self.metrics_listen.map(TcpListener::bind).transpose()?,
+        }))
+    }
+
+    /// Returns the log filter directives, if logging is enabled. The traced
+    /// clients are logged on top of the given filter.
+    pub fn log_directives(&self, env_filter: Option<&str>) -> Option<String> {
+        #[cfg(feature = "otel")]
+        let exported = self.otlp_endpoint.is_some();
+        #[cfg(not(feature = "otel"))]
+        let exported = false;
+        let traced = (!self.trace_client.is_empty()).then(|| format!("{CLIENT_TRACE_TARGET}=info"));
+        let directives = match (self.log_filter.as_deref(), env_filter) {
+            (Some(directives), _) | (None, Some(directives)) => directives,
+            (None, None) if traced.is_some() => return traced,
+            (None, None) if self.log_file.is_some() || exported => "info",
+            (None, None) => return None,
+        };
+        match traced {
+            Some(traced) => Some(format!("{directives},{traced}")),
+            None => Some(directives.to_string()),
+        }
+    }
+
+    /// Returns the tracer configured by the log options.
+    pub fn tracer<'a>(&'a self, directives: &'a str) -> Tracer<'a> {
+        let mut tracer = Tracer::new("payeng", directives)
+            .with_format(self.log_format)
+            .with_ansi(io::stderr().is_terminal());
+        if let Some(path) = &self.log_file {
+            tracer = tracer.with_file(path, self.log_rotation);
+        }
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = &self.otlp_endpoint {
+            tracer = tracer.with_otlp_endpoint(endpoint);
+        }
+        tracer
+    }
+
+    /// Returns the runtime configuration.
+    pub fn config(&self) -> payeng::Result<Config> {
+        let rejections = match &self.rejections {
+            Some(path) => Some(runtime::new_writer(path, self.compression)?),
+            None => None,
+        };
+        let idempotency = match &self.idempotency_store {
+            Some(path) => Some(IdempotencyStore::open(path, self.idempotency_capacity)?),
+            None => None,
+        };
+        let store = match &self.store {
+            Some(path) => Some(LogStore::open(path, self.store_cache)?),
+            None => None,
+        };
+        // The history file is only reused along with the accounts it was recorded for.
+        let restored = store.as_ref().is_some_and(|store| !store.is_empty());
+        let store = store.map(|store| Box::new(store) as Box<dyn AccountStore>);
+        let history = match &self.history {
+            Some(path) if restored => Some(Arc::new(Mutex::new(DiskHistory::open(
+                path,
+                self.history_cache,
+            )?)) as SharedHistory),
+            Some(path) => Some(
+                Arc::new(Mutex::new(DiskHistory::create(path, self.history_cache)?))
+                    as SharedHistory,
+            ),
+            None if self.compact_history => {
+                Some(Arc::new(Mutex::new(CompactHistory::new())) as SharedHistory)
+            }
+            None => None,
+        };
+        let events = match &self.events {
+            Some(path) => Some(Box::new(JsonLinesSink::create(path)?) as Box<dyn EventSink>),
+            None => None,
+        };
+        let snapshots = match &self.snapshot_dir {
+            Some(path) => Some(self.snapshots(path)?),
+            None => None,
+        };
+        Ok(Config {
+            input_format: self.input_format,
+            dialect: CsvDialect {
+                delimiter: self.delimiter,
+                quote: self.quote,
+                has_headers: !self.no_headers,
+                columns: self.columns.clone(),
+                aliases: self.aliases.clone(),
+                flexible: !self.strict_columns,
+                quoted_newlines: !self.single_line_records,
+            },
+            output_format: self.output_format,
+            strict: self.strict,
+            rejections,
+            idempotency,
+            snapshots,
+            events,
+            store,
+            dispute_window: self.dispute_window,
+            history,
+            report_mode: if self.delta {
+                ReportMode::Delta
+            } else {
+                ReportMode::Full
+            },
+            batch_size: self.batch_size,
+            parse_threads: self.parse_threads,
+            mmap: self.mmap,
+            metrics: self.metrics.as_ref().map(|_| Metrics::new()),
+            traced_clients: self
+                .trace_client
+                .iter()
+                .copied()
+                .map(Client::from)
+                .collect(),
+            ..Config::new(self.capacity)
+        })
+    }
+
+    /// Returns the periodic snapshots written into the directory.
+    fn snapshots(&self, directory: &Path) -> payeng::Result<Snapshots> {
+        let policy = SnapshotPolicy {
+            every_transactions: self.snapshot_every,
+            interval: self.snapshot_interval.map(Duration::from_secs),
+            keep: self.snapshot_keep,
+        };
+        let compression = self.compression.unwrap_or(Compression::None);
+        let snapshots = Snapshots::new(directory, self.output_format, compression, policy)?;
+        #[cfg(unix)]
+        snapshots.trigger().on_sigusr1()?;
+        Ok(snapshots)
+    }
+}
+
+/// Parses a single ASCII character argument.
+fn parse_byte(value: &str) -> Result<u8, String> {
+    match value {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if value.len() == 1 && value.is_ascii() => Ok(value.as_bytes()[0]),
+        _ => Err(format!(
+            "expected a single ASCII character, found `{value}`"
+        )),
+    }
+}
+
+/// Parses a transaction column name.
+fn parse_column(value: &str) -> Result<String, String> {
+    let column = value.trim().to_lowercase();
+    if COLUMNS.contains(&column.as_str()) {
+        Ok(column)
+    } else {
+        Err(format!("expected one of {COLUMNS:?}, found `{value}`"))
+    }
+}
+
+/// Parses a header alias in the `alias=column` form.
+fn parse_alias(value: &str) -> Result<(String, String), String> {
+    let (alias, column) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected ALIAS=COLUMN, found `{value}`"))?;
+    Ok((alias.to_string(), parse_column(column)?))
+}
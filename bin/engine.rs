@@ -1,16 +1,36 @@
+use clap::Parser;
+use payeng::compression::Finish;
 use payeng::prelude::runtime;
-use payeng::telemetry::Tracer;
+use payeng::server::Shutdown;
 
-const CAPACITY: usize = 10_000;
+mod cli;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    if let Ok(level_filter) = std::env::var("RUST_LOG") {
-        if level_filter.parse::<tracing::Level>().is_ok() {
-            Tracer::new("payeng", &level_filter).init_subscriber(std::io::stderr)?;
+    let args = cli::Args::parse();
+    let env_filter = std::env::var("RUST_LOG").ok();
+    let _tracer = match args.log_directives(env_filter.as_deref()) {
+        Some(directives) => Some(args.tracer(&directives).init_subscriber(std::io::stderr)?),
+        None => None,
+    };
+
+    let writer = runtime::new_writer(&args.output, args.compression)?;
+    let config = args.config()?;
+    let metrics = config.metrics.clone();
+    match (args.listeners()?, &args.input) {
+        (Some(listeners), _) => {
+            let shutdown = Shutdown::new();
+            shutdown.on_termination()?;
+            runtime::serve(listeners, writer, config, shutdown)?;
+        }
+        (None, Some(input)) => {
+            runtime::run_file(input, writer, config)?;
         }
+        (None, None) => unreachable!("the input is required in file mode"),
+    }
+    if let (Some(metrics), Some(path)) = (metrics, &args.metrics) {
+        let mut writer = runtime::new_writer(path, None)?;
+        metrics.snapshot().write_json(&mut writer)?;
+        writer.finish()?;
     }
-
-    let reader = runtime::get_input()?;
-    runtime::run(reader, std::io::stdout(), CAPACITY);
     Ok(())
 }
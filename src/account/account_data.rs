@@ -3,28 +3,31 @@
 //! This module module defines the transaction data structures.
 //!
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 use parking_lot::Mutex;
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::AccountManager;
+use super::{AccountManager, HistoryEntry, SharedHistory};
 use crate::error::Error;
 use crate::prelude::{Client, Result, TransactionData, TransactionId};
 
 /// [`AccountData`] type represents all the data associated with an account..
-#[derive(Debug, Serialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct AccountData {
     pub client: Client,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
-
-    #[serde(skip_serializing)]
     histories: HashMap<TransactionId, Operation>,
+    /// Disputable transactions in recording order, when the dispute window is per account.
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    window: VecDeque<TransactionId>,
+    /// Shared history index used instead of `histories` when attached.
+    #[serde(skip)]
+    index: Option<SharedHistory>,
 }
 
 impl AccountData {
@@ -36,19 +39,62 @@ impl AccountData {
             total: Default::default(),
             locked: false,
             histories: Default::default(),
+            window: Default::default(),
+            index: None,
+        }
+    }
+}
+
+/// [`AccountSnapshot`] is a point in time copy of an account balances and lock state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountSnapshot {
+    pub client: Client,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl From<&AccountData> for AccountSnapshot {
+    fn from(data: &AccountData) -> Self {
+        Self {
+            client: data.client.clone(),
+            available: data.available,
+            held: data.held,
+            total: data.total,
+            locked: data.locked,
         }
     }
 }
 
+/// [`DisputeState`] is the public view of a recorded transaction state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    Undisputed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// [`TransactionStatus`] describes a recorded transaction and its dispute state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionStatus {
+    pub client: Client,
+    pub tx: TransactionId,
+    pub amount: Decimal,
+    pub state: DisputeState,
+}
+
 /// The [`Operation`] type represents a recorded transaction operation.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Operation {
     amount: Decimal,
     state: State,
 }
 
 // The [`State`] type represents the current state of a recorded transaction.
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 enum State {
     Dispute,
     Resolve,
@@ -56,18 +102,107 @@ enum State {
     None,
 }
 
+impl From<DisputeState> for State {
+    fn from(state: DisputeState) -> Self {
+        match state {
+            DisputeState::Undisputed => Self::None,
+            DisputeState::Disputed => Self::Dispute,
+            DisputeState::Resolved => Self::Resolve,
+            DisputeState::ChargedBack => Self::Final,
+        }
+    }
+}
+
+impl From<&State> for DisputeState {
+    fn from(state: &State) -> Self {
+        match state {
+            State::None => Self::Undisputed,
+            State::Dispute => Self::Disputed,
+            State::Resolve => Self::Resolved,
+            State::Final => Self::ChargedBack,
+        }
+    }
+}
+
 impl AccountData {
+    /// Returns the recorded operation of the transaction if any.
+    fn operation(&self, id: &TransactionId) -> Result<Option<Operation>> {
+        let Some(index) = &self.index else {
+            return Ok(self.histories.get(id).cloned());
+        };
+        let entry = index.lock().get(id)?;
+        Ok(entry
+            .filter(|entry| entry.client == self.client)
+            .map(|entry| Operation {
+                amount: entry.amount,
+                state: State::from(entry.state),
+            }))
+    }
+
+    /// Records the operation of the transaction.
+    fn set_operation(&mut self, id: TransactionId, operation: Operation) -> Result<()> {
+        let Some(index) = &self.index else {
+            self.histories.insert(id, operation);
+            return Ok(());
+        };
+        let entry = HistoryEntry {
+            client: self.client.clone(),
+            amount: operation.amount,
+            state: DisputeState::from(&operation.state),
+        };
+        index.lock().insert(id, entry)
+    }
+
+    /// Removes the transaction from the history unless it is under dispute.
+    fn evict(&mut self, id: &TransactionId) -> Result<bool> {
+        match self.operation(id)? {
+            Some(operation) if operation.state == State::Dispute => Ok(false),
+            Some(_) if self.index.is_some() => {
+                let index = self.index.as_ref().expect("attached history index");
+                index.lock().remove(id)?;
+                Ok(true)
+            }
+            _ => {
+                self.histories.remove(id);
+                Ok(true)
+            }
+        }
+    }
+
     /// Updates transaction history.
-    fn update_history(&mut self, id: TransactionId, amount: Decimal) {
-        self.histories.insert(
+    ///
+    /// The shared index is keyed by transaction id alone, so a transaction id
+    /// already recorded by another client is rejected instead of replacing its entry.
+    fn update_history(&mut self, id: TransactionId, amount: Decimal) -> Result<()> {
+        if let Some(index) = &self.index {
+            if matches!(index.lock().get(&id)?, Some(entry) if entry.client != self.client) {
+                return Err(Error::InvalidTransaction);
+            }
+        }
+        self.set_operation(
             id,
             Operation {
                 amount,
                 state: State::None,
             },
-        );
+        )
     }
 }
+
+impl std::fmt::Debug for AccountData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccountData")
+            .field("client", &self.client)
+            .field("available", &self.available)
+            .field("held", &self.held)
+            .field("total", &self.total)
+            .field("locked", &self.locked)
+            .field("histories", &self.histories.len())
+            .field("index", &self.index.is_some())
+            .finish()
+    }
+}
+
 /// [`Account`] represents a client account.
 pub struct Account {
     state: Mutex<AccountData>,
@@ -75,7 +210,10 @@ pub struct Account {
 
 impl Account {
     /// Creates new account.
-    #[tracing::instrument(name = "create new account")]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "create new account")
+    )]
     pub fn new(client: &Client) -> Self {
         let inner = AccountData::new(client);
         Account {
@@ -83,28 +221,128 @@ impl Account {
         }
     }
 
-    /// Returns a reference to the inner account data.
-    pub(crate) fn inner_ref(&self) -> &Mutex<AccountData> {
-        &self.state
+    /// Returns the status of the transaction if it is recorded in the account history.
+    pub fn transaction_status(&self, id: &TransactionId) -> Option<TransactionStatus> {
+        let guard = self.state.lock();
+        let operation = guard.operation(id).unwrap_or_else(|err| {
+            tracing::error!(err.cause_chain=?err);
+            None
+        });
+        operation.map(|operation| TransactionStatus {
+            client: guard.client.clone(),
+            tx: id.clone(),
+            amount: operation.amount,
+            state: DisputeState::from(&operation.state),
+        })
+    }
+
+    /// Records the transaction history in the shared index instead of the account,
+    /// moving the history already recorded in the account into the index.
+    pub(crate) fn attach_history(&mut self, index: &SharedHistory) -> Result<()> {
+        let mut guard = self.state.lock();
+        if guard.index.is_some() {
+            return Ok(());
+        }
+        guard.index = Some(index.clone());
+        for (id, operation) in std::mem::take(&mut guard.histories) {
+            guard.set_operation(id, operation)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the account client.
+    pub fn client(&self) -> Client {
+        self.state.lock().client.clone()
+    }
+
+    /// Adds the transaction to the account dispute window of the given size, and
+    /// evicts the oldest transactions falling out of it. Returns the evicted ids.
+    pub(crate) fn push_window(
+        &mut self,
+        id: TransactionId,
+        size: usize,
+    ) -> Result<Vec<TransactionId>> {
+        let mut guard = self.state.lock();
+        guard.window.push_back(id);
+        let mut evicted = vec![];
+        let mut remaining = guard.window.len();
+        while guard.window.len() > size && remaining > 0 {
+            remaining -= 1;
+            let Some(id) = guard.window.pop_front() else {
+                break;
+            };
+            if guard.evict(&id)? {
+                evicted.push(id);
+            } else {
+                guard.window.push_back(id);
+            }
+        }
+        Ok(evicted)
+    }
+
+    /// Evicts the transaction from the history unless it is under dispute.
+    /// Returns `false` if the transaction is kept.
+    pub(crate) fn expire(&mut self, id: &TransactionId) -> Result<bool> {
+        self.state.lock().evict(id)
+    }
+
+    /// Returns a snapshot of the account balances.
+    pub fn snapshot(&self) -> AccountSnapshot {
+        AccountSnapshot::from(&*self.state.lock())
+    }
+}
+
+impl Clone for Account {
+    fn clone(&self) -> Self {
+        Account {
+            state: Mutex::new(self.state.lock().clone()),
+        }
+    }
+}
+
+/// An account is serialized with its transaction history, so it can be restored
+/// from an account store.
+impl Serialize for Account {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.state.lock().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Account {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        AccountData::deserialize(deserializer).map(|data| Account {
+            state: Mutex::new(data),
+        })
     }
 }
 
 impl AccountManager for Account {
-    #[tracing::instrument(name = "make deposit", skip(self))]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "make deposit", skip(self))
+    )]
     fn make_deposit(&mut self, transaction: TransactionData) -> Result<()> {
         let TransactionData { id, amount, .. } = transaction;
         // Deposit transactions are guarantee to have some amount due to validation.
         // So it's okay to unwrap the value here.
         let amount = amount.unwrap();
         let mut guard = self.state.lock();
+        guard.update_history(id, amount)?;
         guard.total += amount;
         guard.available += amount;
-        guard.update_history(id, amount);
 
         Ok(())
     }
 
-    #[tracing::instrument(name = "withdraw transaction", skip(self))]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "withdraw transaction", skip(self))
+    )]
     fn withdraw(&mut self, transaction: TransactionData) -> Result<()> {
         let TransactionData { id, amount, .. } = transaction;
         // Withdrawal transactions are guarantee to have some amount due to validation.
@@ -115,20 +353,23 @@ impl AccountManager for Account {
         if amount > guard.available {
             return Err(Error::WithdrawalError);
         }
+        guard.update_history(id, amount)?;
         guard.total -= amount;
         guard.available -= amount;
-        guard.update_history(id, amount);
         Ok(())
     }
 
-    #[tracing::instrument(name = "dispute transaction", skip(self))]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "dispute transaction", skip(self))
+    )]
     fn dispute(&mut self, tx_id: TransactionId) -> Result<()> {
         let mut guard = self.state.lock();
-        match guard.histories.entry(tx_id) {
-            Entry::Occupied(mut entry) => {
-                let operation = entry.get_mut();
+        match guard.operation(&tx_id)? {
+            Some(mut operation) => {
                 operation.state = State::Dispute;
                 let amount = operation.amount;
+                guard.set_operation(tx_id, operation)?;
                 guard.held += amount;
                 guard.total += amount;
                 Ok(())
@@ -137,14 +378,17 @@ impl AccountManager for Account {
         }
     }
 
-    #[tracing::instrument(name = "resolve transaction", skip(self))]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "resolve transaction", skip(self))
+    )]
     fn resolve(&mut self, tx_id: TransactionId) -> Result<()> {
         let mut guard = self.state.lock();
-        match guard.histories.entry(tx_id) {
-            Entry::Occupied(mut entry) if entry.get().state == State::Dispute => {
-                let operation = entry.get_mut();
+        match guard.operation(&tx_id)? {
+            Some(mut operation) if operation.state == State::Dispute => {
                 operation.state = State::Resolve;
                 let amount = operation.amount;
+                guard.set_operation(tx_id, operation)?;
                 guard.held -= amount;
                 guard.available += amount;
                 Ok(())
@@ -153,14 +397,17 @@ impl AccountManager for Account {
         }
     }
 
-    #[tracing::instrument(name = "charge back transaction", skip(self))]
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "charge back transaction", skip(self))
+    )]
     fn charge_back(&mut self, tx_id: TransactionId) -> Result<()> {
         let mut guard = self.state.lock();
-        match guard.histories.entry(tx_id) {
-            Entry::Occupied(mut entry) if entry.get().state == State::Dispute => {
-                let operation = entry.get_mut();
+        match guard.operation(&tx_id)? {
+            Some(mut operation) if operation.state == State::Dispute => {
                 operation.state = State::Final;
                 let amount = operation.amount;
+                guard.set_operation(tx_id, operation)?;
                 guard.held -= amount;
                 guard.total -= amount;
                 guard.locked = true;
@@ -188,6 +435,7 @@ mod test {
                 tx_type,
                 id: TransactionId::from(id),
                 amount,
+                idempotency_key: None,
             }
         }
     }
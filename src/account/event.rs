@@ -0,0 +1,231 @@
+//! Account events.
+//!
+//! This module defines the [`AccountEvent`] type published for every successful
+//! account operation, and the [`EventSink`] trait which delivers the events to
+//! downstream consumers, along with channel, JSON lines file and callback sinks.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crossbeam::channel;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::AccountSnapshot;
+use crate::compression::Finish;
+use crate::error::Error;
+use crate::format::{Encoder, JsonEncoder};
+use crate::prelude::{Client, TransactionId, TransactionType};
+use crate::Result;
+
+/// [`AccountEventKind`] is the kind of change applied to an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountEventKind {
+    DepositApplied,
+    WithdrawalApplied,
+    DisputeOpened,
+    DisputeResolved,
+    ChargedBack,
+    AccountLocked,
+}
+
+impl From<&TransactionType> for AccountEventKind {
+    fn from(tx_type: &TransactionType) -> Self {
+        match tx_type {
+            TransactionType::Deposit => Self::DepositApplied,
+            TransactionType::Withdrawal => Self::WithdrawalApplied,
+            TransactionType::Dispute => Self::DisputeOpened,
+            TransactionType::Resolve => Self::DisputeResolved,
+            TransactionType::ChargeBack => Self::ChargedBack,
+        }
+    }
+}
+
+/// [`AccountEvent`] describes a change applied to an account, along with the
+/// account balances and lock state after the change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountEvent {
+    pub event: AccountEventKind,
+    pub client: Client,
+    pub tx: TransactionId,
+    pub amount: Option<Decimal>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl AccountEvent {
+    /// Creates new [`AccountEvent`] for the transaction applied to the account.
+    pub fn new(
+        event: AccountEventKind,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+        account: AccountSnapshot,
+    ) -> Self {
+        let AccountSnapshot {
+            client,
+            available,
+            held,
+            total,
+            locked,
+        } = account;
+        Self {
+            event,
+            client,
+            tx,
+            amount,
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+}
+
+/// The [`EventSink`] trait specifies how account events are delivered.
+pub trait EventSink: Send {
+    fn publish(&mut self, event: &AccountEvent) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: EventSink + ?Sized> EventSink for Box<S> {
+    fn publish(&mut self, event: &AccountEvent) -> Result<()> {
+        (**self).publish(event)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// [`ChannelSink`] sends the events over a channel.
+#[derive(Debug, Clone)]
+pub struct ChannelSink(channel::Sender<AccountEvent>);
+
+impl ChannelSink {
+    /// Creates new [`ChannelSink`] sending the events on the channel.
+    pub fn new(sender: channel::Sender<AccountEvent>) -> Self {
+        Self(sender)
+    }
+}
+
+impl EventSink for ChannelSink {
+    fn publish(&mut self, event: &AccountEvent) -> Result<()> {
+        self.0
+            .send(event.clone())
+            .map_err(|e| Error::SendError(e.to_string()))
+    }
+}
+
+/// [`JsonLinesSink`] writes the events as JSON lines.
+pub struct JsonLinesSink<W: io::Write>(JsonEncoder<W>);
+
+impl<W> JsonLinesSink<W>
+where
+    W: io::Write,
+{
+    /// Creates new [`JsonLinesSink`] writing the events to the writer.
+    pub fn new(writer: W) -> Self {
+        Self(JsonEncoder::new(writer))
+    }
+}
+
+impl JsonLinesSink<File> {
+    /// Creates new [`JsonLinesSink`] writing the events to the file, truncating it.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        File::create(path).map(Self::new).map_err(Error::IoError)
+    }
+}
+
+impl<W> EventSink for JsonLinesSink<W>
+where
+    W: Finish + Send,
+{
+    fn publish(&mut self, event: &AccountEvent) -> Result<()> {
+        self.0.encode(event)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Encoder::<AccountEvent>::flush(&mut self.0)
+    }
+}
+
+/// [`CallbackSink`] calls a function for every event.
+pub struct CallbackSink<F>(F);
+
+impl<F> CallbackSink<F>
+where
+    F: FnMut(&AccountEvent) + Send,
+{
+    /// Creates new [`CallbackSink`] calling the function for every event.
+    pub fn new(callback: F) -> Self {
+        Self(callback)
+    }
+}
+
+impl<F> EventSink for CallbackSink<F>
+where
+    F: FnMut(&AccountEvent) + Send,
+{
+    fn publish(&mut self, event: &AccountEvent) -> Result<()> {
+        (self.0)(event);
+        Ok(())
+    }
+}
+
+impl<F> fmt::Debug for CallbackSink<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackSink").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> AccountEvent {
+        AccountEvent::new(
+            AccountEventKind::DepositApplied,
+            TransactionId::from(1),
+            Some(Decimal::from(2)),
+            AccountSnapshot {
+                client: Client::from(1),
+                available: Decimal::from(2),
+                held: Decimal::ZERO,
+                total: Decimal::from(2),
+                locked: false,
+            },
+        )
+    }
+
+    #[test]
+    fn publish_events_to_every_sink() {
+        let (sender, receiver) = channel::unbounded();
+        ChannelSink::new(sender).publish(&event()).unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), event());
+
+        let mut seen = vec![];
+        CallbackSink::new(|event: &AccountEvent| seen.push(event.clone()))
+            .publish(&event())
+            .unwrap();
+        assert_eq!(seen, [event()]);
+
+        let mut content = vec![];
+        let mut sink = JsonLinesSink::new(&mut content);
+        sink.publish(&event()).unwrap();
+        sink.flush().unwrap();
+        drop(sink);
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "{\"event\":\"deposit_applied\",\"client\":1,\"tx\":1,\"amount\":\"2\",\
+             \"available\":\"2\",\"held\":\"0\",\"total\":\"2\",\"locked\":false}\n"
+        );
+    }
+}
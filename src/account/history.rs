@@ -0,0 +1,583 @@
+//! Transaction history index.
+//!
+//! This module defines the [`HistoryIndex`] trait which stores the deposits and
+//! withdrawals of every account, keyed by [`TransactionId`], for the disputes to
+//! look them up. By default each account keeps its own history in memory; when an
+//! index is attached to the registry, the accounts use it instead.
+//!
+//! [`CompactHistory`] keeps the history of every account in a single open-addressing
+//! table. The columns of the table are stored in separate vectors and the amounts
+//! as 64-bit mantissas with their scale, so an entry takes 15 bytes per slot,
+//! against 24 bytes per slot of a per-account `HashMap` along with the account
+//! lock and table overheads. Amounts whose mantissa does not fit 64 bits are kept
+//! aside.
+//!
+//! [`DiskHistory`] keeps the history in a file of fixed-size records addressed by
+//! transaction id, with an in-memory LRU cache of the recently used entries. The
+//! file is sparse, so only the pages holding recorded transactions use disk space.
+//! The records are keyed by transaction id alone, so an existing file is only
+//! meaningful along with the accounts it was recorded for.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use rust_decimal::Decimal;
+
+use super::DisputeState;
+use crate::error::Error;
+use crate::prelude::{Client, TransactionId};
+use crate::Result;
+
+/// A history index shared by the accounts of a registry.
+pub type SharedHistory = Arc<Mutex<dyn HistoryIndex>>;
+
+/// [`HistoryEntry`] is a recorded deposit or withdrawal and its dispute state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub client: Client,
+    pub amount: Decimal,
+    pub state: DisputeState,
+}
+
+/// The [`HistoryIndex`] trait specifies the behavior of a transaction history store.
+pub trait HistoryIndex: Send {
+    /// Returns the entry recorded for the transaction if any.
+    fn get(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>>;
+
+    /// Records the entry of the transaction, replacing the previous entry if any.
+    fn insert(&mut self, id: TransactionId, entry: HistoryEntry) -> Result<()>;
+
+    /// Removes the entry of the transaction and returns it.
+    fn remove(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>>;
+
+    /// Writes the pending entries to the backing storage, if any.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Tag of a free slot of the [`CompactHistory`] table.
+const EMPTY: u8 = 0;
+/// Tag of a removed slot of the [`CompactHistory`] table.
+const REMOVED: u8 = 1;
+/// Bit set in the tag of an occupied slot, along with the amount scale and the
+/// dispute state.
+const OCCUPIED: u8 = 0x80;
+/// Initial number of slots of the [`CompactHistory`] table.
+const INITIAL_SLOTS: usize = 1024;
+
+/// [`CompactHistory`] type. See module level [documentation](self).
+#[derive(Debug, Default)]
+pub struct CompactHistory {
+    ids: Vec<u32>,
+    mantissas: Vec<i64>,
+    clients: Vec<u16>,
+    /// Slot tags: empty, removed, or occupied with the amount scale and dispute state.
+    tags: Vec<u8>,
+    len: usize,
+    removed: usize,
+    /// Entries whose amount does not fit the table.
+    overflow: HashMap<TransactionId, HistoryEntry>,
+}
+
+impl CompactHistory {
+    /// Creates new empty [`CompactHistory`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates new empty [`CompactHistory`] sized for `capacity` entries.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut history = Self::default();
+        history.allocate((capacity * 8 / 7 + 1).next_power_of_two());
+        history
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.len + self.overflow.len()
+    }
+
+    /// Returns `true` if the history holds no entry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of bytes allocated by the history.
+    pub fn allocated_bytes(&self) -> usize {
+        let slot = mem::size_of::<u32>()
+            + mem::size_of::<i64>()
+            + mem::size_of::<u16>()
+            + mem::size_of::<u8>();
+        let entry = mem::size_of::<TransactionId>() + mem::size_of::<HistoryEntry>();
+        self.tags.capacity() * slot + self.overflow.capacity() * entry
+    }
+
+    /// Replaces the table with an empty table of `slots` slots.
+    fn allocate(&mut self, slots: usize) {
+        self.ids = vec![0; slots];
+        self.mantissas = vec![0; slots];
+        self.clients = vec![0; slots];
+        self.tags = vec![EMPTY; slots];
+        self.len = 0;
+        self.removed = 0;
+    }
+
+    /// Returns the first slot probed for the transaction.
+    fn home(&self, id: u32) -> usize {
+        // Fibonacci hashing: the high bits of the product depend on every bit of
+        // the id, so strided ids spread over the table.
+        let bits = self.tags.len().trailing_zeros();
+        (u64::from(id).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - bits)) as usize
+    }
+
+    /// Returns the slot holding the transaction, or the slot where to insert it.
+    fn probe(&self, id: u32) -> (usize, bool) {
+        let mask = self.tags.len() - 1;
+        let mut slot = self.home(id);
+        let mut free = None;
+        loop {
+            match self.tags[slot] {
+                EMPTY => return (free.unwrap_or(slot), false),
+                REMOVED => {
+                    free.get_or_insert(slot);
+                }
+                _ if self.ids[slot] == id => return (slot, true),
+                _ => {}
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+
+    /// Grows the table, or rehashes it in place when it is cluttered with
+    /// removed slots, to keep it at most 7/8 full.
+    fn reserve(&mut self) {
+        if self.tags.is_empty() {
+            self.allocate(INITIAL_SLOTS);
+            return;
+        }
+        if (self.len + self.removed + 1) * 8 <= self.tags.len() * 7 {
+            return;
+        }
+        let slots = if (self.len + 1) * 2 > self.tags.len() {
+            self.tags.len() * 2
+        } else {
+            self.tags.len()
+        };
+        let ids = mem::take(&mut self.ids);
+        let mantissas = mem::take(&mut self.mantissas);
+        let clients = mem::take(&mut self.clients);
+        let tags = mem::take(&mut self.tags);
+        self.allocate(slots);
+        for slot in 0..tags.len() {
+            if tags[slot] & OCCUPIED != 0 {
+                let (free, _) = self.probe(ids[slot]);
+                self.ids[free] = ids[slot];
+                self.mantissas[free] = mantissas[slot];
+                self.clients[free] = clients[slot];
+                self.tags[free] = tags[slot];
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Returns the entry stored in the occupied slot.
+    fn entry(&self, slot: usize) -> HistoryEntry {
+        let tag = self.tags[slot];
+        let scale = u32::from((tag >> 2) & 0x1f);
+        HistoryEntry {
+            client: Client::from(self.clients[slot]),
+            amount: Decimal::from_i128_with_scale(self.mantissas[slot].into(), scale),
+            state: decode_state(tag & 0x03),
+        }
+    }
+}
+
+impl HistoryIndex for CompactHistory {
+    fn get(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>> {
+        if self.tags.is_empty() {
+            return Ok(self.overflow.get(id).cloned());
+        }
+        match self.probe(*id.inner_ref()) {
+            (slot, true) => Ok(Some(self.entry(slot))),
+            _ => Ok(self.overflow.get(id).cloned()),
+        }
+    }
+
+    fn insert(&mut self, id: TransactionId, entry: HistoryEntry) -> Result<()> {
+        let Ok(mantissa) = i64::try_from(entry.amount.mantissa()) else {
+            self.remove(&id)?;
+            self.overflow.insert(id, entry);
+            return Ok(());
+        };
+        self.overflow.remove(&id);
+        self.reserve();
+        let (slot, found) = self.probe(*id.inner_ref());
+        if !found {
+            if self.tags[slot] == REMOVED {
+                self.removed -= 1;
+            }
+            self.len += 1;
+        }
+        // The scale of a decimal is at most 28 and fits 5 bits.
+        let scale = entry.amount.scale() as u8;
+        self.ids[slot] = *id.inner_ref();
+        self.mantissas[slot] = mantissa;
+        self.clients[slot] = entry.client.0;
+        self.tags[slot] = OCCUPIED | scale << 2 | encode_state(&entry.state);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>> {
+        if !self.tags.is_empty() {
+            if let (slot, true) = self.probe(*id.inner_ref()) {
+                let entry = self.entry(slot);
+                self.tags[slot] = REMOVED;
+                self.len -= 1;
+                self.removed += 1;
+                return Ok(Some(entry));
+            }
+        }
+        Ok(self.overflow.remove(id))
+    }
+}
+
+/// Size of a history record on disk: a presence flag, the dispute state, the
+/// client id and the serialized amount.
+const RECORD_SIZE: usize = 20;
+
+/// A cached history entry.
+#[derive(Debug)]
+struct Cached {
+    entry: HistoryEntry,
+    dirty: bool,
+    stamp: u64,
+}
+
+/// [`DiskHistory`] type. See module level [documentation](self).
+#[derive(Debug)]
+pub struct DiskHistory {
+    file: File,
+    cache: HashMap<TransactionId, Cached>,
+    /// Cache accesses in least recently used order. An access is stale when the
+    /// entry has been used again since.
+    order: VecDeque<(TransactionId, u64)>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl DiskHistory {
+    /// Creates an empty [`DiskHistory`] at `path`, truncating the file if it
+    /// exists. At most `capacity` entries are cached in memory.
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        Self::with_options(path, capacity, true)
+    }
+
+    /// Opens the [`DiskHistory`] persisted at `path`, creating the file if needed.
+    /// At most `capacity` entries are cached in memory.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        Self::with_options(path, capacity, false)
+    }
+
+    fn with_options(path: impl AsRef<Path>, capacity: usize, truncate: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(truncate)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::IoError)?;
+        Ok(Self {
+            file,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+            capacity: capacity.max(1),
+        })
+    }
+
+    /// Returns the number of cached entries.
+    pub fn cached(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Caches the entry as the most recently used one, evicting the least
+    /// recently used entries beyond the capacity.
+    fn cache(&mut self, id: TransactionId, entry: HistoryEntry, dirty: bool) -> Result<()> {
+        self.clock += 1;
+        let stamp = self.clock;
+        let dirty = dirty || matches!(self.cache.get(&id), Some(cached) if cached.dirty);
+        self.cache.insert(
+            id.clone(),
+            Cached {
+                entry,
+                dirty,
+                stamp,
+            },
+        );
+        self.order.push_back((id, stamp));
+
+        while self.cache.len() > self.capacity {
+            let Some((id, stamp)) = self.order.pop_front() else {
+                break;
+            };
+            if matches!(self.cache.get(&id), Some(cached) if cached.stamp == stamp) {
+                if let Some(cached) = self.cache.remove(&id) {
+                    if cached.dirty {
+                        self.write(&id, Some(&cached.entry))?;
+                    }
+                }
+            }
+        }
+        if self.order.len() > 4 * self.capacity {
+            self.order.retain(
+                |(id, stamp)| matches!(self.cache.get(id), Some(cached) if cached.stamp == *stamp),
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the offset of the transaction record.
+    fn offset(id: &TransactionId) -> u64 {
+        u64::from(*id.inner_ref()) * RECORD_SIZE as u64
+    }
+
+    /// Reads the transaction record.
+    fn read(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>> {
+        let offset = Self::offset(id);
+        let len = self.file.metadata().map_err(Error::IoError)?.len();
+        if offset + RECORD_SIZE as u64 > len {
+            return Ok(None);
+        }
+        let mut record = [0; RECORD_SIZE];
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::IoError)?;
+        self.file.read_exact(&mut record).map_err(Error::IoError)?;
+        Ok(decode(&record))
+    }
+
+    /// Writes the transaction record, or clears it.
+    fn write(&mut self, id: &TransactionId, entry: Option<&HistoryEntry>) -> Result<()> {
+        let record = entry.map_or([0; RECORD_SIZE], encode);
+        self.file
+            .seek(SeekFrom::Start(Self::offset(id)))
+            .map_err(Error::IoError)?;
+        self.file.write_all(&record).map_err(Error::IoError)
+    }
+}
+
+impl HistoryIndex for DiskHistory {
+    fn get(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>> {
+        if let Some(cached) = self.cache.get(id) {
+            let entry = cached.entry.clone();
+            self.cache(id.clone(), entry.clone(), false)?;
+            return Ok(Some(entry));
+        }
+        match self.read(id)? {
+            Some(entry) => {
+                self.cache(id.clone(), entry.clone(), false)?;
+                Ok(Some(entry))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&mut self, id: TransactionId, entry: HistoryEntry) -> Result<()> {
+        self.cache(id, entry, true)
+    }
+
+    fn remove(&mut self, id: &TransactionId) -> Result<Option<HistoryEntry>> {
+        let entry = match self.cache.remove(id) {
+            Some(cached) => Some(cached.entry),
+            None => self.read(id)?,
+        };
+        if entry.is_some() {
+            self.write(id, None)?;
+        }
+        Ok(entry)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let dirty = self
+            .cache
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(id, cached)| (id.clone(), cached.entry.clone()))
+            .collect::<Vec<_>>();
+        for (id, entry) in &dirty {
+            self.write(id, Some(entry))?;
+        }
+        for cached in self.cache.values_mut() {
+            cached.dirty = false;
+        }
+        self.file.sync_data().map_err(Error::IoError)
+    }
+}
+
+/// Encodes the entry into a history record.
+fn encode(entry: &HistoryEntry) -> [u8; RECORD_SIZE] {
+    let mut record = [0; RECORD_SIZE];
+    record[0] = 1;
+    record[1] = encode_state(&entry.state);
+    record[2..4].copy_from_slice(&entry.client.0.to_le_bytes());
+    record[4..].copy_from_slice(&entry.amount.serialize());
+    record
+}
+
+/// Decodes a history record, returning `None` for a cleared record.
+fn decode(record: &[u8; RECORD_SIZE]) -> Option<HistoryEntry> {
+    if record[0] == 0 {
+        return None;
+    }
+    let state = decode_state(record[1]);
+    let client = u16::from_le_bytes([record[2], record[3]]);
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&record[4..]);
+    Some(HistoryEntry {
+        client: Client::from(client),
+        amount: Decimal::deserialize(amount),
+        state,
+    })
+}
+
+/// Encodes the dispute state on two bits.
+fn encode_state(state: &DisputeState) -> u8 {
+    match state {
+        DisputeState::Undisputed => 0,
+        DisputeState::Disputed => 1,
+        DisputeState::Resolved => 2,
+        DisputeState::ChargedBack => 3,
+    }
+}
+
+/// Decodes a dispute state encoded with [`encode_state`].
+fn decode_state(state: u8) -> DisputeState {
+    match state {
+        0 => DisputeState::Undisputed,
+        1 => DisputeState::Disputed,
+        2 => DisputeState::Resolved,
+        _ => DisputeState::ChargedBack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(client: u16, amount: i64) -> HistoryEntry {
+        HistoryEntry {
+            client: Client::from(client),
+            amount: Decimal::new(amount, 4),
+            state: DisputeState::Undisputed,
+        }
+    }
+
+    #[test]
+    fn store_entries_in_compact_table() {
+        let mut history = CompactHistory::new();
+        for id in 1..=10_000 {
+            history
+                .insert(TransactionId::from(id), entry(id as u16, id.into()))
+                .unwrap();
+        }
+        for id in (1..=10_000).step_by(2) {
+            assert_eq!(
+                history.remove(&TransactionId::from(id)).unwrap(),
+                Some(entry(id as u16, id.into()))
+            );
+        }
+        let huge = HistoryEntry {
+            amount: Decimal::MAX,
+            ..entry(7, 0)
+        };
+        history
+            .insert(TransactionId::from(2), huge.clone())
+            .unwrap();
+        let precise = HistoryEntry {
+            amount: Decimal::new(15, 1),
+            state: DisputeState::Disputed,
+            ..entry(9, 0)
+        };
+        history
+            .insert(TransactionId::from(9), precise.clone())
+            .unwrap();
+
+        assert_eq!(history.len(), 5_001);
+        assert_eq!(history.get(&TransactionId::from(2)).unwrap(), Some(huge));
+        let restored = history.get(&TransactionId::from(9)).unwrap().unwrap();
+        assert_eq!(restored, precise);
+        assert_eq!(restored.amount.to_string(), "1.5");
+        assert_eq!(history.get(&TransactionId::from(3)).unwrap(), None);
+        assert_eq!(
+            history.get(&TransactionId::from(4)).unwrap(),
+            Some(entry(4, 4))
+        );
+        assert!(history.allocated_bytes() / history.len() < 64);
+    }
+
+    #[test]
+    fn spread_strided_ids_over_compact_table() {
+        let mut history = CompactHistory::with_capacity(4096);
+        for id in (0..4096).map(|id| id << 16) {
+            history
+                .insert(TransactionId::from(id), entry(1, 1))
+                .unwrap();
+        }
+        let mut probes = 0;
+        for id in (0..4096).map(|id| id << 16) {
+            let (slot, found) = history.probe(id);
+            assert!(found);
+            probes += (slot + history.tags.len() - history.home(id)) % history.tags.len();
+        }
+        assert!(probes < 4 * 4096, "too many probes: {probes}");
+    }
+
+    #[test]
+    fn spill_least_recently_used_entries_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.bin");
+        let mut history = DiskHistory::open(&path, 2).unwrap();
+        for id in 1..=5 {
+            history
+                .insert(TransactionId::from(id), entry(id as u16, id.into()))
+                .unwrap();
+        }
+        assert_eq!(history.cached(), 2);
+
+        let mut disputed = history.get(&TransactionId::from(1)).unwrap().unwrap();
+        assert_eq!(disputed, entry(1, 1));
+        disputed.state = DisputeState::Disputed;
+        history
+            .insert(TransactionId::from(1), disputed.clone())
+            .unwrap();
+        assert_eq!(
+            history.remove(&TransactionId::from(3)).unwrap(),
+            Some(entry(3, 3))
+        );
+        assert_eq!(history.get(&TransactionId::from(3)).unwrap(), None);
+        assert_eq!(history.get(&TransactionId::from(42)).unwrap(), None);
+        history.flush().unwrap();
+        drop(history);
+
+        let mut history = DiskHistory::open(&path, 2).unwrap();
+        assert_eq!(
+            history.get(&TransactionId::from(1)).unwrap(),
+            Some(disputed)
+        );
+        assert_eq!(
+            history.get(&TransactionId::from(5)).unwrap(),
+            Some(entry(5, 5))
+        );
+        drop(history);
+
+        let mut history = DiskHistory::create(&path, 2).unwrap();
+        assert_eq!(history.get(&TransactionId::from(1)).unwrap(), None);
+        assert_eq!(history.get(&TransactionId::from(5)).unwrap(), None);
+    }
+}
@@ -1,7 +1,17 @@
 pub mod account_data;
+pub mod event;
+pub mod history;
 pub mod manager;
 pub mod registry;
+pub mod store;
+pub mod window;
 
-pub use account_data::Account;
+pub use account_data::{Account, AccountSnapshot, DisputeState, TransactionStatus};
+pub use event::{
+    AccountEvent, AccountEventKind, CallbackSink, ChannelSink, EventSink, JsonLinesSink,
+};
+pub use history::{CompactHistory, DiskHistory, HistoryEntry, HistoryIndex, SharedHistory};
 pub use manager::AccountManager;
-pub use registry::AccountRegistry;
+pub use registry::{AccountRegistry, Accounts, ReportMode, SharedRegistry};
+pub use store::{AccountStore, LogStore, MemoryStore};
+pub use window::{DisputeWindow, ExpiredTransactions};
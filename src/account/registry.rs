@@ -1,17 +1,53 @@
 //! Account registry.
 //!
 //! This module defines the account registry type which is a collection of all the accounts
-//! in the system.
+//! in the system. The accounts are kept by an [`AccountStore`], in memory by default.
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
 
-use super::Account;
-use crate::prelude::Client;
+use parking_lot::RwLock;
+
+use super::{
+    Account, AccountStore, DisputeWindow, ExpiredTransactions, MemoryStore, SharedHistory,
+    TransactionStatus,
+};
+use crate::prelude::{Client, TransactionId};
+use crate::Result;
+
+/// An account registry shared between the transaction writer and the readers.
+pub type SharedRegistry = Arc<RwLock<AccountRegistry>>;
+
+/// [`ReportMode`] selects the accounts written in a report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReportMode {
+    /// Every account in the registry.
+    #[default]
+    Full,
+    /// Only the accounts changed since the last report.
+    Delta,
+}
+
+/// An iterator over accounts, loaded from the account store.
+pub type Accounts<'a> = Box<dyn Iterator<Item = Result<Cow<'a, Account>>> + 'a>;
 
 /// [`AccountRegistry]` type. See module level [documentation](self).
-#[derive(Default)]
-pub struct AccountRegistry(HashMap<Client, Account>);
+pub struct AccountRegistry {
+    store: Box<dyn AccountStore>,
+    changed: HashSet<Client>,
+    window: DisputeWindow,
+    /// Disputable transactions in input order, when the dispute window is global.
+    recent: VecDeque<(Client, TransactionId)>,
+    expired: ExpiredTransactions,
+    history: Option<SharedHistory>,
+}
+
+impl Default for AccountRegistry {
+    fn default() -> Self {
+        Self::with_store(Box::new(MemoryStore::new()))
+    }
+}
 
 impl AccountRegistry {
     /// Creates new account registry.
@@ -19,18 +55,155 @@ impl AccountRegistry {
         Self::default()
     }
 
+    /// Creates new account registry keeping the accounts in the given store.
+    pub fn with_store(store: Box<dyn AccountStore>) -> Self {
+        Self {
+            store,
+            changed: HashSet::new(),
+            window: DisputeWindow::default(),
+            recent: VecDeque::new(),
+            expired: ExpiredTransactions::default(),
+            history: None,
+        }
+    }
+
+    /// Returns the dispute window bounding the transaction history.
+    pub fn dispute_window(&self) -> DisputeWindow {
+        self.window
+    }
+
+    /// Sets the dispute window bounding the transaction history.
+    pub fn set_dispute_window(&mut self, window: DisputeWindow) {
+        self.window = window;
+    }
+
     /// Returns an mutable reference to the client account.
     /// If the account is not present, insert a new account and returns the reference
     /// to new inserted account.
-    pub fn get_mut_or_insert(&mut self, client: Client) -> &mut Account {
-        match self.0.entry(client.clone()) {
-            Entry::Occupied(account) => account.into_mut(),
-            Entry::Vacant(e) => e.insert(Account::new(&client)),
-        }
+    pub fn get_mut_or_insert(&mut self, client: Client) -> Result<&mut Account> {
+        self.account_mut(&client)
+    }
+
+    /// Records the account transaction histories in the shared index instead of
+    /// the accounts.
+    pub fn set_history(&mut self, history: SharedHistory) {
+        self.history = Some(history);
+    }
+
+    /// Returns the shared history index if any.
+    pub fn history(&self) -> Option<SharedHistory> {
+        self.history.clone()
+    }
+
+    /// Returns the client account if any.
+    pub fn get(&self, client: &Client) -> Result<Option<Cow<'_, Account>>> {
+        self.store.get(client)
+    }
+
+    /// Returns `true` if the registry holds the client account.
+    pub fn contains(&self, client: &Client) -> bool {
+        self.store.contains(client)
+    }
+
+    /// Returns the status of the transaction recorded in the shared history
+    /// index. Without an index the account histories are not searched, and the
+    /// transaction is never found.
+    pub fn find_transaction(&self, id: &TransactionId) -> Result<Option<TransactionStatus>> {
+        let Some(history) = &self.history else {
+            return Ok(None);
+        };
+        let entry = history.lock().get(id)?;
+        // Entries of unknown clients are left over from another run.
+        let entry = entry.filter(|entry| self.store.contains(&entry.client));
+        Ok(entry.map(|entry| TransactionStatus {
+            client: entry.client,
+            tx: id.clone(),
+            amount: entry.amount,
+            state: entry.state,
+        }))
     }
 
     /// Returns an iterator over the accounts in the registry.
-    pub fn iter(&self) -> impl Iterator<Item = &Account> {
-        self.0.values()
+    pub fn iter(&self) -> Accounts<'_> {
+        self.store.iter()
+    }
+
+    /// Returns an iterator over the accounts selected by the report mode.
+    pub fn report(&self, mode: ReportMode) -> Accounts<'_> {
+        match mode {
+            ReportMode::Full => self.iter(),
+            ReportMode::Delta => Box::new(
+                self.changed
+                    .iter()
+                    .filter_map(|client| self.store.get(client).transpose()),
+            ),
+        }
+    }
+
+    /// Marks the client account as changed since the last report and since it
+    /// was last persisted.
+    pub fn mark_changed(&mut self, client: Client) {
+        self.store.mark_dirty(&client);
+        self.changed.insert(client);
+    }
+
+    /// Clears the changed accounts once they have been reported.
+    pub fn clear_changed(&mut self) {
+        self.changed.clear();
+    }
+
+    /// Records a disputable transaction in the dispute window, evicting the
+    /// transactions falling out of it from the account histories.
+    pub fn record_transaction(&mut self, client: &Client, id: TransactionId) -> Result<()> {
+        match self.window {
+            DisputeWindow::Unbounded => {}
+            DisputeWindow::PerAccount(size) => {
+                let account = self.account_mut(client)?;
+                let evicted = account.push_window(id, size)?;
+                self.store.mark_dirty(client);
+                for id in evicted {
+                    self.expired.insert(&id);
+                }
+            }
+            DisputeWindow::Global(size) => {
+                self.recent.push_back((client.clone(), id));
+                let mut remaining = self.recent.len();
+                while self.recent.len() > size && remaining > 0 {
+                    remaining -= 1;
+                    let Some((client, id)) = self.recent.pop_front() else {
+                        break;
+                    };
+                    if self.account_mut(&client)?.expire(&id)? {
+                        self.store.mark_dirty(&client);
+                        self.expired.insert(&id);
+                    } else {
+                        self.recent.push_back((client, id));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the transaction was evicted from the dispute window.
+    pub fn is_expired(&self, id: &TransactionId) -> bool {
+        self.expired.contains(id)
+    }
+
+    /// Persists the accounts in the store.
+    pub fn persist(&mut self) -> Result<()> {
+        if let Some(history) = &self.history {
+            history.lock().flush()?;
+        }
+        self.store.persist()
+    }
+
+    /// Returns the client account, attaching the shared history index if any.
+    fn account_mut(&mut self, client: &Client) -> Result<&mut Account> {
+        let account = self.store.get_mut_or_insert(client)?;
+        if let Some(history) = &self.history {
+            account.attach_history(history)?;
+        }
+        Ok(account)
     }
 }
@@ -0,0 +1,483 @@
+//! Account storage backends.
+//!
+//! This module defines the [`AccountStore`] trait which abstracts where the
+//! [`AccountRegistry`](super::AccountRegistry) keeps its accounts, along with two
+//! implementations:
+//!
+//! - [`MemoryStore`], the default, keeps every account in a `HashMap`.
+//! - [`LogStore`] keeps the least recently used accounts in memory and writes the
+//!   others to an append-only log file of JSON lines, so the client population is
+//!   not limited by the available memory. Only the new accounts and the accounts
+//!   marked dirty since they were last written are appended on eviction. The accounts are restored from the log
+//!   when the store is reopened. The log is compacted when most of its records are
+//!   superseded.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+
+use super::Account;
+use crate::error::Error;
+use crate::prelude::Client;
+use crate::Result;
+
+/// The [`AccountStore`] trait specifies the behavior of an account storage backend.
+pub trait AccountStore: Send + Sync {
+    /// Returns the client account if any.
+    fn get(&self, client: &Client) -> Result<Option<Cow<'_, Account>>>;
+
+    /// Returns `true` if the store holds the client account.
+    fn contains(&self, client: &Client) -> bool;
+
+    /// Returns a mutable reference to the client account, inserting a new
+    /// account if the client is unknown. The caller marks the account with
+    /// [`mark_dirty`](Self::mark_dirty) when it changes it.
+    fn get_mut_or_insert(&mut self, client: &Client) -> Result<&mut Account>;
+
+    /// Marks the client account as changed since it was last persisted. This is a
+    /// no-op for in-memory stores.
+    fn mark_dirty(&mut self, _client: &Client) {}
+
+    /// Inserts the account, replacing the client account if any.
+    fn insert(&mut self, account: Account) -> Result<()>;
+
+    /// Returns an iterator over the accounts in the store.
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Cow<'_, Account>>> + '_>;
+
+    /// Persists the accounts. This is a no-op for in-memory stores.
+    fn persist(&mut self) -> Result<()>;
+}
+
+/// [`MemoryStore`] keeps every account in memory.
+#[derive(Default)]
+pub struct MemoryStore(HashMap<Client, Account>);
+
+impl MemoryStore {
+    /// Creates new empty [`MemoryStore`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn get(&self, client: &Client) -> Result<Option<Cow<'_, Account>>> {
+        Ok(self.0.get(client).map(Cow::Borrowed))
+    }
+
+    fn contains(&self, client: &Client) -> bool {
+        self.0.contains_key(client)
+    }
+
+    fn get_mut_or_insert(&mut self, client: &Client) -> Result<&mut Account> {
+        Ok(self
+            .0
+            .entry(client.clone())
+            .or_insert_with(|| Account::new(client)))
+    }
+
+    fn insert(&mut self, account: Account) -> Result<()> {
+        self.0.insert(account.client(), account);
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Cow<'_, Account>>> + '_> {
+        Box::new(self.0.values().map(|account| Ok(Cow::Borrowed(account))))
+    }
+
+    fn persist(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An account kept in memory by the [`LogStore`].
+struct Resident {
+    account: Account,
+    /// Whether the account changed since it was last appended to the log.
+    dirty: bool,
+    stamp: u64,
+}
+
+/// [`LogStore`] type. See module level [documentation](self).
+pub struct LogStore {
+    path: PathBuf,
+    /// Unbuffered, so that evicted accounts can be read back right away.
+    writer: File,
+    reader: Mutex<BufReader<File>>,
+    /// Offset of the latest record of every account written to the log.
+    offsets: HashMap<Client, u64>,
+    /// End offset of the log.
+    end: u64,
+    /// Number of records superseded by a later record.
+    stale: usize,
+    resident: HashMap<Client, Resident>,
+    /// Resident accesses in least recently used order. An access is stale when
+    /// the account has been used again since.
+    order: VecDeque<(Client, u64)>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl LogStore {
+    /// Opens the [`LogStore`] persisted at `path`, creating the file if needed.
+    /// At most `capacity` accounts are kept in memory.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .map_err(Error::IoError)?;
+
+        let mut offsets = HashMap::new();
+        let mut stale = 0;
+        let mut end = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(Error::IoError)?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                tracing::warn!(offset = end, "ignored truncated account record");
+                break;
+            }
+            let account = serde_json::from_str::<Account>(&line)?;
+            if offsets.insert(account.client(), end).is_some() {
+                stale += 1;
+            }
+            end += read as u64;
+        }
+        file.set_len(end).map_err(Error::IoError)?;
+
+        Ok(Self {
+            reader: Mutex::new(Self::reader(&path)?),
+            path,
+            writer: file,
+            offsets,
+            end,
+            stale,
+            resident: HashMap::new(),
+            order: VecDeque::new(),
+            clock: 0,
+            capacity: capacity.max(1),
+        })
+    }
+
+    /// Returns the number of accounts in the store.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+            + self
+                .resident
+                .keys()
+                .filter(|client| !self.offsets.contains_key(client))
+                .count()
+    }
+
+    /// Returns `true` if the store holds no account.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reads the account record at the offset.
+    fn read(reader: &mut BufReader<File>, offset: u64) -> Result<Account> {
+        reader
+            .seek(SeekFrom::Start(offset))
+            .map_err(Error::IoError)?;
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(Error::IoError)?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Opens a reader on the log.
+    fn reader(path: &Path) -> Result<BufReader<File>> {
+        File::open(path).map(BufReader::new).map_err(Error::IoError)
+    }
+
+    /// Appends the account record to the log, compacting the log when most of
+    /// its records are superseded.
+    fn append(&mut self, account: &Account) -> Result<()> {
+        let mut record = serde_json::to_vec(account)?;
+        record.push(b'\n');
+        self.writer.write_all(&record).map_err(Error::IoError)?;
+        if self.offsets.insert(account.client(), self.end).is_some() {
+            self.stale += 1;
+        }
+        self.end += record.len() as u64;
+
+        if self.stale > self.offsets.len() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Marks the resident account as the most recently used one.
+    fn touch(&mut self, client: &Client) {
+        if let Some(resident) = self.resident.get_mut(client) {
+            self.clock += 1;
+            resident.stamp = self.clock;
+            self.order.push_back((client.clone(), self.clock));
+        }
+    }
+
+    /// Makes the account resident as the most recently used one, evicting the
+    /// least recently used accounts to the log beyond the capacity.
+    fn make_resident(&mut self, account: Account, dirty: bool) -> Result<()> {
+        let client = account.client();
+        let resident = Resident {
+            account,
+            dirty,
+            stamp: 0,
+        };
+        self.resident.insert(client.clone(), resident);
+        self.touch(&client);
+
+        while self.resident.len() > self.capacity {
+            let Some((client, stamp)) = self.order.pop_front() else {
+                break;
+            };
+            if matches!(self.resident.get(&client), Some(resident) if resident.stamp == stamp) {
+                if let Some(resident) = self.resident.remove(&client) {
+                    if resident.dirty {
+                        self.append(&resident.account)?;
+                    }
+                }
+            }
+        }
+        if self.order.len() > 4 * self.capacity {
+            self.order.retain(|(client, stamp)| {
+                matches!(self.resident.get(client), Some(resident) if resident.stamp == *stamp)
+            });
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with the latest record of every account.
+    fn compact(&mut self) -> Result<()> {
+        let compacted = self.path.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&compacted).map_err(Error::IoError)?);
+        let reader = self.reader.get_mut();
+        let mut offsets = HashMap::with_capacity(self.offsets.len());
+        let mut end = 0;
+        for (client, offset) in &self.offsets {
+            let mut record = serde_json::to_vec(&Self::read(reader, *offset)?)?;
+            record.push(b'\n');
+            writer.write_all(&record).map_err(Error::IoError)?;
+            offsets.insert(client.clone(), end);
+            end += record.len() as u64;
+        }
+        writer.flush().map_err(Error::IoError)?;
+        writer.get_ref().sync_all().map_err(Error::IoError)?;
+        fs::rename(&compacted, &self.path).map_err(Error::IoError)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .map_err(Error::IoError)?;
+        self.writer = file;
+        *self.reader.get_mut() = Self::reader(&self.path)?;
+        self.offsets = offsets;
+        self.end = end;
+        self.stale = 0;
+        Ok(())
+    }
+}
+
+impl AccountStore for LogStore {
+    fn get(&self, client: &Client) -> Result<Option<Cow<'_, Account>>> {
+        if let Some(resident) = self.resident.get(client) {
+            return Ok(Some(Cow::Borrowed(&resident.account)));
+        }
+        match self.offsets.get(client) {
+            Some(offset) => Ok(Some(Cow::Owned(Self::read(
+                &mut self.reader.lock(),
+                *offset,
+            )?))),
+            None => Ok(None),
+        }
+    }
+
+    fn contains(&self, client: &Client) -> bool {
+        self.resident.contains_key(client) || self.offsets.contains_key(client)
+    }
+
+    fn get_mut_or_insert(&mut self, client: &Client) -> Result<&mut Account> {
+        if self.resident.contains_key(client) {
+            self.touch(client);
+        } else {
+            match self.offsets.get(client) {
+                Some(offset) => {
+                    let account = Self::read(self.reader.get_mut(), *offset)?;
+                    self.make_resident(account, false)?;
+                }
+                None => self.make_resident(Account::new(client), true)?,
+            }
+        }
+        let resident = self
+            .resident
+            .get_mut(client)
+            .expect("resident account after loading");
+        Ok(&mut resident.account)
+    }
+
+    fn mark_dirty(&mut self, client: &Client) {
+        if let Some(resident) = self.resident.get_mut(client) {
+            resident.dirty = true;
+        }
+    }
+
+    fn insert(&mut self, account: Account) -> Result<()> {
+        let client = account.client();
+        match self.resident.get_mut(&client) {
+            Some(resident) => {
+                resident.account = account;
+                resident.dirty = true;
+                self.touch(&client);
+            }
+            None => self.make_resident(account, true)?,
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<Cow<'_, Account>>> + '_> {
+        let resident = self
+            .resident
+            .values()
+            .map(|resident| Ok(Cow::Borrowed(&resident.account)));
+        let evicted = self
+            .offsets
+            .iter()
+            .filter(|(client, _)| !self.resident.contains_key(client))
+            .map(|(_, offset)| Self::read(&mut self.reader.lock(), *offset).map(Cow::Owned));
+        Box::new(resident.chain(evicted))
+    }
+
+    #[tracing::instrument(name = "Persist account log", skip(self))]
+    fn persist(&mut self) -> Result<()> {
+        let mut resident = std::mem::take(&mut self.resident);
+        let appended = resident
+            .values_mut()
+            .filter(|resident| resident.dirty)
+            .try_for_each(|resident| {
+                resident.dirty = false;
+                self.append(&resident.account)
+            });
+        self.resident = resident;
+        appended?;
+        self.writer.sync_all().map_err(Error::IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::prelude::{AccountManager, TransactionData, TransactionId, TransactionType};
+
+    fn deposit(store: &mut dyn AccountStore, client: u16, tx: u32, amount: i64) {
+        let client = Client::from(client);
+        let deposit = TransactionData {
+            client: client.clone(),
+            tx_type: TransactionType::Deposit,
+            id: TransactionId::from(tx),
+            amount: Some(Decimal::from(amount)),
+            idempotency_key: None,
+        };
+        store
+            .get_mut_or_insert(&client)
+            .unwrap()
+            .make_deposit(deposit)
+            .unwrap();
+        store.mark_dirty(&client);
+    }
+
+    fn available(store: &dyn AccountStore, client: u16) -> Decimal {
+        let account = store.get(&Client::from(client)).unwrap().unwrap();
+        account.snapshot().available
+    }
+
+    #[test]
+    fn evict_accounts_beyond_capacity_to_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LogStore::open(dir.path().join("accounts.log"), 2).unwrap();
+        for client in 1..=5 {
+            deposit(&mut store, client, client.into(), client.into());
+        }
+        deposit(&mut store, 1, 6, 10);
+
+        assert_eq!(store.resident.len(), 2);
+        assert_eq!(store.len(), 5);
+        assert_eq!(available(&store, 1), Decimal::from(11));
+        assert_eq!(available(&store, 3), Decimal::from(3));
+        assert_eq!(store.iter().count(), 5);
+    }
+
+    #[test]
+    fn evict_least_recently_used_accounts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LogStore::open(dir.path().join("accounts.log"), 2).unwrap();
+        deposit(&mut store, 1, 1, 1);
+        deposit(&mut store, 2, 2, 2);
+        deposit(&mut store, 1, 3, 3);
+        deposit(&mut store, 3, 4, 4);
+
+        assert!(store.resident.contains_key(&Client::from(1)));
+        assert!(!store.resident.contains_key(&Client::from(2)));
+        assert_eq!(available(&store, 2), Decimal::from(2));
+    }
+
+    #[test]
+    fn append_only_changed_accounts_on_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = LogStore::open(dir.path().join("accounts.log"), 1).unwrap();
+        deposit(&mut store, 1, 1, 1);
+        store.persist().unwrap();
+        let end = store.end;
+
+        deposit(&mut store, 2, 2, 2);
+        assert_eq!(store.end, end, "unchanged account should not be appended");
+        assert_eq!(available(&store, 1), Decimal::from(1));
+
+        store.get_mut_or_insert(&Client::from(1)).unwrap();
+        let end = store.end;
+        deposit(&mut store, 3, 3, 3);
+        assert_eq!(store.end, end, "accessed account should not be appended");
+    }
+
+    #[test]
+    fn restore_accounts_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.log");
+
+        let mut store = LogStore::open(&path, 1).unwrap();
+        for round in 0..4 {
+            for client in 1..=3 {
+                deposit(&mut store, client, round * 10 + u32::from(client), 1);
+            }
+        }
+        assert!(
+            store.stale <= store.offsets.len(),
+            "log should be compacted"
+        );
+        store.persist().unwrap();
+        drop(store);
+
+        let mut store = LogStore::open(&path, 1).unwrap();
+        assert_eq!(store.len(), 3);
+        for client in 1..=3 {
+            assert_eq!(available(&store, client), Decimal::from(4));
+        }
+        let status = store
+            .get_mut_or_insert(&Client::from(2))
+            .unwrap()
+            .transaction_status(&TransactionId::from(32));
+        assert!(status.is_some(), "history should be restored");
+    }
+}
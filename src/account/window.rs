@@ -0,0 +1,117 @@
+//! Dispute window.
+//!
+//! This module defines the [`DisputeWindow`] type which bounds the transaction
+//! history kept for disputes. Transactions falling out of the window are evicted
+//! from the account history and can no longer be disputed. Transactions under
+//! dispute are kept until the dispute is settled.
+//!
+//! The evicted transaction ids are remembered in [`ExpiredTransactions`], a set of
+//! id ranges which stays small since transaction ids mostly grow with the input.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::prelude::TransactionId;
+
+/// [`DisputeWindow`] specifies how many deposits and withdrawals remain disputable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Every transaction remains disputable.
+    #[default]
+    Unbounded,
+    /// The N most recent transactions of each account remain disputable.
+    PerAccount(usize),
+    /// The N most recent transactions in the input sequence, across all
+    /// accounts, remain disputable.
+    Global(usize),
+}
+
+impl FromStr for DisputeWindow {
+    type Err = String;
+
+    /// Parses `unbounded`, `account:N` or `global:N`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse = |size: &str| {
+            size.parse()
+                .map_err(|_| format!("invalid dispute window size `{size}`"))
+        };
+        match value.split_once(':') {
+            None if value == "unbounded" => Ok(Self::Unbounded),
+            Some(("account", size)) => parse(size).map(Self::PerAccount),
+            Some(("global", size)) => parse(size).map(Self::Global),
+            _ => Err(format!(
+                "expected `unbounded`, `account:N` or `global:N`, found `{value}`"
+            )),
+        }
+    }
+}
+
+/// [`ExpiredTransactions`] is the set of transaction ids evicted from the dispute window.
+#[derive(Debug, Default)]
+pub struct ExpiredTransactions {
+    /// Inclusive id ranges, keyed by their first id.
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl ExpiredTransactions {
+    /// Adds the transaction id to the set.
+    pub fn insert(&mut self, id: &TransactionId) {
+        let id = *id.inner_ref();
+        if self.contains_id(id) {
+            return;
+        }
+        let (mut start, mut end) = (id, id);
+        if let Some((&prev_start, &prev_end)) = self.ranges.range(..id).next_back() {
+            if prev_end.checked_add(1) == Some(id) {
+                start = prev_start;
+            }
+        }
+        if let Some(next_end) = id.checked_add(1).and_then(|next| self.ranges.remove(&next)) {
+            end = next_end;
+        }
+        self.ranges.insert(start, end);
+    }
+
+    /// Returns `true` if the transaction id is in the set.
+    pub fn contains(&self, id: &TransactionId) -> bool {
+        self.contains_id(*id.inner_ref())
+    }
+
+    /// Returns the number of id ranges in the set.
+    pub fn ranges(&self) -> usize {
+        self.ranges.len()
+    }
+
+    fn contains_id(&self, id: u32) -> bool {
+        matches!(self.ranges.range(..=id).next_back(), Some((_, &end)) if id <= end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dispute_window() {
+        assert_eq!("unbounded".parse(), Ok(DisputeWindow::Unbounded));
+        assert_eq!("account:5".parse(), Ok(DisputeWindow::PerAccount(5)));
+        assert_eq!("global:100".parse(), Ok(DisputeWindow::Global(100)));
+        assert!("global".parse::<DisputeWindow>().is_err());
+        assert!("account:x".parse::<DisputeWindow>().is_err());
+    }
+
+    #[test]
+    fn merge_contiguous_expired_ids() {
+        let mut expired = ExpiredTransactions::default();
+        for id in [1, 2, 3, 7, 5, 6, 4, 10] {
+            expired.insert(&TransactionId::from(id));
+        }
+        assert_eq!(expired.ranges(), 2);
+        for id in 1..=7 {
+            assert!(expired.contains(&TransactionId::from(id)));
+        }
+        assert!(!expired.contains(&TransactionId::from(0)));
+        assert!(!expired.contains(&TransactionId::from(8)));
+        assert!(expired.contains(&TransactionId::from(10)));
+    }
+}
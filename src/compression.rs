@@ -0,0 +1,286 @@
+//! Compression type.
+//!
+//! This module defines the [`Compression`] type used to transparently
+//! decompress transaction inputs and compress account reports, along with the
+//! [`Finish`] trait which completes a report output. A compressed stream is only
+//! valid once its trailer is written by [`Finish::finish`].
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::error::Error;
+use crate::Result;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// [`Compression`] represents the supported compression codecs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the compression associated with the path extension if any.
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "gz" | "gzip" => Some(Self::Gzip),
+            "zst" | "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Returns the file extension of the compression if any.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+        }
+    }
+
+    /// Returns the compression identified by the leading magic bytes.
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if bytes.starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Wraps the reader into a streaming decoder.
+    pub fn decoder<R>(self, reader: R) -> Result<Box<dyn Read + Send>>
+    where
+        R: BufRead + Send + 'static,
+    {
+        let decoder: Box<dyn Read + Send> = match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::with_buffer(reader).map_err(Error::IoError)?),
+        };
+        Ok(decoder)
+    }
+
+    /// Wraps the writer into a streaming encoder.
+    ///
+    /// The compression trailer is written by [`Finish::finish`].
+    pub fn encoder<W>(self, writer: W) -> Result<CompressedWriter<W>>
+    where
+        W: Finish,
+    {
+        let codec = match self {
+            Self::None => Codec::None(writer),
+            Self::Gzip => Codec::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Self::Zstd => Codec::Zstd(
+                zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .map_err(Error::IoError)?,
+            ),
+        };
+        Ok(CompressedWriter {
+            codec,
+            finished: false,
+        })
+    }
+}
+
+/// The [`Finish`] trait specifies how a report output is completed.
+///
+/// Writers are flushed by default. Compressed writers also write their trailer,
+/// after which nothing can be written.
+pub trait Finish: Write {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Finish for io::Sink {}
+impl Finish for io::Stdout {}
+impl Finish for io::Stderr {}
+impl Finish for File {}
+impl Finish for Vec<u8> {}
+
+impl<W: Finish> Finish for BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_mut().finish()
+    }
+}
+
+impl<W: Finish + ?Sized> Finish for Box<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<W: Finish + ?Sized> Finish for &mut W {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// The codec of a [`CompressedWriter`].
+enum Codec<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+/// A writer compressing the stream with a [`Compression`] codec.
+///
+/// A writer dropped before being finished still writes its trailer, ignoring
+/// any error.
+pub struct CompressedWriter<W: Finish> {
+    codec: Codec<W>,
+    finished: bool,
+}
+
+impl<W: Finish> CompressedWriter<W> {
+    /// Writes the trailer of the compressed stream.
+    fn finish_codec(&mut self) -> io::Result<()> {
+        match &mut self.codec {
+            Codec::None(_) => Ok(()),
+            Codec::Gzip(encoder) => encoder.try_finish(),
+            Codec::Zstd(encoder) => encoder.do_finish(),
+        }
+    }
+}
+
+impl<W: Finish> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other(
+                "write after the compressed stream is finished",
+            ));
+        }
+        match &mut self.codec {
+            Codec::None(writer) => writer.write(buf),
+            Codec::Gzip(encoder) => encoder.write(buf),
+            Codec::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.codec {
+            Codec::None(writer) => writer.flush(),
+            Codec::Gzip(encoder) => encoder.flush(),
+            Codec::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl<W: Finish> Finish for CompressedWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.finish_codec()?;
+            self.finished = true;
+        }
+        match &mut self.codec {
+            Codec::None(writer) => writer.finish(),
+            Codec::Gzip(encoder) => encoder.get_mut().finish(),
+            Codec::Zstd(encoder) => encoder.get_mut().finish(),
+        }
+    }
+}
+
+impl<W: Finish> Drop for CompressedWriter<W> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish_codec();
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(Self::None),
+            "gzip" | "gz" => Ok(Self::Gzip),
+            "zstd" | "zst" => Ok(Self::Zstd),
+            _ => Err(Error::UnknownCompression(value.into())),
+        }
+    }
+}
+
+/// Returns the compression of the file, detected from the path extension or,
+/// failing that, from the magic bytes of the file.
+pub fn detect_file(path: impl AsRef<Path>) -> Result<Compression> {
+    let path = path.as_ref();
+    if let Some(compression) = Compression::from_path(path) {
+        return Ok(compression);
+    }
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    std::fs::File::open(path)
+        .and_then(|file| file.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic))
+        .map_err(Error::IoError)?;
+    Ok(Compression::from_magic(&magic))
+}
+
+/// Returns a decoding reader, detecting the compression from the path extension
+/// or, failing that, from the magic bytes of the stream.
+pub fn detect_decoder<R>(reader: R, path: Option<&Path>) -> Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+{
+    let mut reader = BufReader::new(reader);
+    let compression = match path.and_then(Compression::from_path) {
+        Some(compression) => compression,
+        None => Compression::from_magic(reader.fill_buf().map_err(Error::IoError)?),
+    };
+    compression.decoder(reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const DATA: &str = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+
+    fn compress(compression: Compression) -> Vec<u8> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = compression.encoder(file.reopen().unwrap()).unwrap();
+        encoder.write_all(DATA.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+        assert!(encoder.write_all(DATA.as_bytes()).is_err());
+        drop(encoder);
+        std::fs::read(file.path()).unwrap()
+    }
+
+    #[test]
+    fn detect_compression_from_extension() {
+        assert_eq!(Compression::from_path("tx.csv.gz"), Some(Compression::Gzip));
+        assert_eq!(
+            Compression::from_path("tx.csv.zst"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_path("tx.csv"), None);
+        assert_eq!(Compression::from_path("tx"), None);
+    }
+
+    #[test]
+    fn roundtrip_through_magic_bytes_detection() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let content = compress(compression);
+            assert_eq!(Compression::from_magic(&content), compression);
+
+            let mut decoded = String::new();
+            detect_decoder(Cursor::new(content), None)
+                .unwrap()
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, DATA, "roundtrip failed for {compression:?}");
+        }
+    }
+}
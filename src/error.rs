@@ -11,6 +11,23 @@ pub enum Error {
     #[error("non disputed transaction")]
     DisputeStateError,
 
+    #[error("transaction is outside the dispute window")]
+    DisputeWindowExpired,
+
+    #[error("malformed record at line {line}: {record}: {source}")]
+    MalformedRecord {
+        line: u64,
+        record: String,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("missing or invalid {0} field")]
+    InvalidField(&'static str),
+
+    #[error("found record with {found} fields, but the previous record has {expected} fields")]
+    UnequalLengths { expected: usize, found: usize },
+
     #[error("failed to send transaction: {0}")]
     SendError(String),
 
@@ -20,14 +37,90 @@ pub enum Error {
     #[error(transparent)]
     CsvError(#[from] csv::Error),
 
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+
+    #[cfg(feature = "arrow")]
+    #[error(transparent)]
+    ArrowError(#[from] arrow_schema::ArrowError),
+
     #[error(transparent)]
     TracerError(#[from] tracing::subscriber::SetGlobalDefaultError),
+
+    #[error("invalid telemetry configuration: {0}")]
+    TelemetryConfig(String),
+
     #[error("account already exists")]
     AccountError,
 
     #[error(transparent)]
     IoError(std::io::Error),
 
+    #[error("http server error: {0}")]
+    HttpError(String),
+
+    #[error("unknown compression: {0}")]
+    UnknownCompression(String),
+
+    #[error("unknown format: {0}")]
+    UnknownFormat(String),
+
+    #[error("{0:?} cannot be used as input format")]
+    UnsupportedInputFormat(crate::format::Format),
+
+    #[error("the report encoder is finished")]
+    EncoderFinished,
+
     #[error("expected 1 argument, found none")]
     InvalidArgumentError,
+
+    #[error("idempotency key reused with a different transaction")]
+    IdempotencyKeyReused,
+
+    #[error("a parsing thread panicked")]
+    WorkerPanicked,
+}
+
+impl Error {
+    /// Returns `true` if the error comes from reading or writing the underlying
+    /// input or output.
+    pub fn is_io_error(&self) -> bool {
+        match self {
+            Self::IoError(_) => true,
+            Self::CsvError(err) => err.is_io_error(),
+            Self::MalformedRecord { source, .. } => source.is_io_error(),
+            _ => false,
+        }
+    }
+
+    /// Returns a short stable name of the error kind, suitable as a metric label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidTransaction => "invalid_transaction",
+            Self::WithdrawalError => "insufficient_funds",
+            Self::DisputeStateError => "dispute_state",
+            Self::DisputeWindowExpired => "dispute_window_expired",
+            Self::MalformedRecord { .. } => "malformed_record",
+            Self::InvalidField(_) => "invalid_field",
+            Self::UnequalLengths { .. } => "unequal_lengths",
+            Self::SendError(_) => "send",
+            Self::RecvError(_) => "recv",
+            Self::CsvError(_) => "csv",
+            Self::JsonError(_) => "json",
+            #[cfg(feature = "arrow")]
+            Self::ArrowError(_) => "arrow",
+            Self::TracerError(_) => "tracer",
+            Self::TelemetryConfig(_) => "telemetry_config",
+            Self::AccountError => "account",
+            Self::IoError(_) => "io",
+            Self::HttpError(_) => "http",
+            Self::UnknownCompression(_) => "unknown_compression",
+            Self::UnknownFormat(_) => "unknown_format",
+            Self::UnsupportedInputFormat(_) => "unsupported_input_format",
+            Self::EncoderFinished => "encoder_finished",
+            Self::InvalidArgumentError => "invalid_argument",
+            Self::IdempotencyKeyReused => "idempotency_key_reused",
+            Self::WorkerPanicked => "worker_panicked",
+        }
+    }
 }
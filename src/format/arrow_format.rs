@@ -0,0 +1,331 @@
+//! Arrow IPC format.
+//!
+//! This module defines the Arrow IPC [`Encoder`] implementation which writes
+//! reports as typed columnar files with fixed scale decimal columns. Amounts
+//! with more than [`DECIMAL_SCALE`] decimal places are rejected rather than
+//! rounded.
+//!
+
+use std::io;
+use std::sync::Arc;
+
+use arrow_array::builder::{
+    BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder, UInt32Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use rust_decimal::Decimal;
+
+use super::Encoder;
+use crate::compression::Finish;
+use crate::error::Error;
+use crate::prelude::{AccountSnapshot, Rejection};
+use crate::Result;
+
+/// Precision of the decimal columns.
+pub const DECIMAL_PRECISION: u8 = 38;
+
+/// Scale of the decimal columns.
+pub const DECIMAL_SCALE: i8 = 4;
+
+/// Number of records buffered before a record batch is written.
+const BATCH_SIZE: usize = 8192;
+
+/// The [`Columnar`] trait specifies how records are laid out in Arrow record batches.
+pub trait Columnar: Clone {
+    /// Returns the fixed schema of the record.
+    fn schema() -> Schema;
+
+    /// Checks that the record can be converted into a record batch.
+    fn validate(&self) -> Result<()>;
+
+    /// Converts the records into a record batch with the record schema.
+    fn to_batch(records: &[Self]) -> Result<RecordBatch>;
+}
+
+/// Returns a decimal column field.
+fn decimal_field(name: &str, nullable: bool) -> Field {
+    Field::new(
+        name,
+        DataType::Decimal128(DECIMAL_PRECISION, DECIMAL_SCALE),
+        nullable,
+    )
+}
+
+/// Returns a decimal column builder.
+fn decimal_builder(capacity: usize) -> Result<Decimal128Builder> {
+    Ok(Decimal128Builder::with_capacity(capacity)
+        .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE)?)
+}
+
+/// Returns the decimal value as an integer scaled to [`DECIMAL_SCALE`]. Fails
+/// if the value has more decimal places than the scale.
+fn scaled(value: Decimal) -> Result<i128> {
+    let mut value = value.normalize();
+    if value.scale() > DECIMAL_SCALE as u32 {
+        return Err(ArrowError::InvalidArgumentError(format!(
+            "{value} has more than {DECIMAL_SCALE} decimal places"
+        ))
+        .into());
+    }
+    value.rescale(DECIMAL_SCALE as u32);
+    Ok(value.mantissa())
+}
+
+impl Columnar for AccountSnapshot {
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Field::new("client", DataType::UInt16, false),
+            decimal_field("available", false),
+            decimal_field("held", false),
+            decimal_field("total", false),
+            Field::new("locked", DataType::Boolean, false),
+        ])
+    }
+
+    fn validate(&self) -> Result<()> {
+        scaled(self.available)?;
+        scaled(self.held)?;
+        scaled(self.total)?;
+        Ok(())
+    }
+
+    fn to_batch(records: &[Self]) -> Result<RecordBatch> {
+        let mut client = UInt16Builder::with_capacity(records.len());
+        let mut available = decimal_builder(records.len())?;
+        let mut held = decimal_builder(records.len())?;
+        let mut total = decimal_builder(records.len())?;
+        let mut locked = BooleanBuilder::with_capacity(records.len());
+
+        for record in records {
+            client.append_value(record.client.0);
+            available.append_value(scaled(record.available)?);
+            held.append_value(scaled(record.held)?);
+            total.append_value(scaled(record.total)?);
+            locked.append_value(record.locked);
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(client.finish()),
+            Arc::new(available.finish()),
+            Arc::new(held.finish()),
+            Arc::new(total.finish()),
+            Arc::new(locked.finish()),
+        ];
+        Ok(RecordBatch::try_new(Arc::new(Self::schema()), columns)?)
+    }
+}
+
+impl Columnar for Rejection {
+    fn schema() -> Schema {
+        Schema::new(vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("type", DataType::Utf8, false),
+            Field::new("tx", DataType::UInt32, false),
+            decimal_field("amount", true),
+            Field::new("reason", DataType::Utf8, false),
+        ])
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.amount.map(scaled).transpose()?;
+        Ok(())
+    }
+
+    fn to_batch(records: &[Self]) -> Result<RecordBatch> {
+        let mut client = UInt16Builder::with_capacity(records.len());
+        let mut tx_type = StringBuilder::new();
+        let mut tx = UInt32Builder::with_capacity(records.len());
+        let mut amount = decimal_builder(records.len())?;
+        let mut reason = StringBuilder::new();
+
+        for record in records {
+            client.append_value(record.client.0);
+            tx_type.append_value(record.tx_type.to_string());
+            tx.append_value(*record.tx.inner_ref());
+            amount.append_option(record.amount.map(scaled).transpose()?);
+            reason.append_value(&record.reason);
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(client.finish()),
+            Arc::new(tx_type.finish()),
+            Arc::new(tx.finish()),
+            Arc::new(amount.finish()),
+            Arc::new(reason.finish()),
+        ];
+        Ok(RecordBatch::try_new(Arc::new(Self::schema()), columns)?)
+    }
+}
+
+/// The state of the underline Arrow IPC file writer.
+enum State<W: io::Write> {
+    Pending(W),
+    Writing(Box<FileWriter<W>>),
+    Finished,
+}
+
+/// A report encoder writing an Arrow IPC file.
+///
+/// Records are buffered and written in batches. The file footer is written
+/// when the encoder is finished, after which no more records can be encoded.
+pub struct ArrowEncoder<W: io::Write, T> {
+    state: State<W>,
+    records: Vec<T>,
+}
+
+impl<W, T> ArrowEncoder<W, T>
+where
+    W: io::Write,
+    T: Columnar,
+{
+    /// Creates new [`ArrowEncoder`] with the underline writer.
+    pub fn new(writer: W) -> Self {
+        Self {
+            state: State::Pending(writer),
+            records: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Writes the buffered records as a record batch. The buffer is emptied
+    /// even if the batch cannot be written.
+    fn write_batch(&mut self) -> Result<()> {
+        let batch = if self.records.is_empty() {
+            None
+        } else {
+            let batch = T::to_batch(&self.records);
+            self.records.clear();
+            Some(batch?)
+        };
+        if let State::Pending(_) = self.state {
+            if let State::Pending(writer) = std::mem::replace(&mut self.state, State::Finished) {
+                // The file writer takes the writer, which is lost if the header
+                // cannot be written.
+                let writer = FileWriter::try_new(writer, &T::schema())?;
+                self.state = State::Writing(Box::new(writer));
+            }
+        }
+        if let (State::Writing(writer), Some(batch)) = (&mut self.state, batch) {
+            writer.write(&batch)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W, T> Encoder<T> for ArrowEncoder<W, T>
+where
+    W: Finish,
+    T: Columnar,
+{
+    fn encode(&mut self, record: &T) -> Result<()> {
+        if let State::Finished = self.state {
+            return Err(Error::EncoderFinished);
+        }
+        record.validate()?;
+        self.records.push(record.clone());
+        if self.records.len() >= BATCH_SIZE {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_batch()?;
+        if let State::Writing(writer) = &mut self.state {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.write_batch()?;
+        if let State::Writing(mut writer) = std::mem::replace(&mut self.state, State::Finished) {
+            writer.finish()?;
+            writer.get_mut().finish().map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::Decimal128Type;
+    use arrow_ipc::reader::FileReader;
+
+    use super::*;
+    use crate::prelude::Client;
+
+    #[test]
+    fn write_account_report_with_decimal_columns() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = ArrowEncoder::new(file.reopen().unwrap());
+        for (client, available, held) in [(1, "1.5", "0"), (2, "2.25", "0.0001")] {
+            let available: Decimal = available.parse().unwrap();
+            let held: Decimal = held.parse().unwrap();
+            encoder
+                .encode(&AccountSnapshot {
+                    client: Client::from(client),
+                    available,
+                    held,
+                    total: available + held,
+                    locked: client == 2,
+                })
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        assert!(matches!(
+            encoder.encode(&AccountSnapshot {
+                client: Client::from(3),
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                total: Decimal::ZERO,
+                locked: false,
+            }),
+            Err(Error::EncoderFinished)
+        ));
+
+        let reader = FileReader::try_new(file.reopen().unwrap(), None).unwrap();
+        assert_eq!(*reader.schema(), AccountSnapshot::schema());
+        let batches = reader.collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+
+        let total = batches[0].column(3).as_primitive::<Decimal128Type>();
+        assert_eq!(total.value_as_string(0), "1.5000");
+        assert_eq!(total.value_as_string(1), "2.2501");
+        let locked = batches[0].column(4).as_boolean();
+        assert!(!locked.value(0) && locked.value(1));
+    }
+
+    #[test]
+    fn reject_amounts_beyond_the_decimal_scale() {
+        assert_eq!(scaled("1.50000".parse().unwrap()).unwrap(), 15000);
+        assert!(scaled("0.00001".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn keep_encoding_after_a_rejected_record() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = ArrowEncoder::new(file.reopen().unwrap());
+        let snapshot = |client: u16, available: &str| {
+            let available: Decimal = available.parse().unwrap();
+            AccountSnapshot {
+                client: Client::from(client),
+                available,
+                held: Decimal::ZERO,
+                total: available,
+                locked: false,
+            }
+        };
+        assert!(encoder.encode(&snapshot(1, "0.00001")).is_err());
+        encoder.encode(&snapshot(2, "2.5")).unwrap();
+        encoder.flush().unwrap();
+        encoder.finish().unwrap();
+
+        let reader = FileReader::try_new(file.reopen().unwrap(), None).unwrap();
+        let batches = reader.collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 1);
+    }
+}
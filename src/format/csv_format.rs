@@ -0,0 +1,312 @@
+//! CSV format.
+//!
+//! This module defines the CSV [`Decoder`] and [`Encoder`] implementations.
+//!
+
+use std::io;
+
+use csv::{ByteRecord, ReaderBuilder, Trim, WriterBuilder};
+use serde::Serialize;
+
+use super::{Decoder, Encoder};
+use crate::compression::Finish;
+use crate::error::Error;
+use crate::prelude::TransactionData;
+use crate::Result;
+
+/// The transaction columns in their default order.
+pub const COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// [`CsvDialect`] describes how a CSV transaction input is laid out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvDialect {
+    /// Field delimiter.
+    pub delimiter: u8,
+    /// Quote character.
+    pub quote: u8,
+    /// Whether the first record is a header record.
+    pub has_headers: bool,
+    /// Column order used when the input has no header record.
+    pub columns: Vec<String>,
+    /// Header aliases as `(alias, column)` pairs, matched case insensitively.
+    pub aliases: Vec<(String, String)>,
+    /// Whether records may have a different number of fields than the header.
+    pub flexible: bool,
+    /// Whether quoted fields may contain line breaks. Otherwise every line is a
+    /// record, which lets the input be split at any line.
+    pub quoted_newlines: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: b'"',
+            has_headers: true,
+            columns: COLUMNS.iter().map(|column| column.to_string()).collect(),
+            aliases: vec![],
+            flexible: true,
+            quoted_newlines: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Returns the header record with the aliases resolved to the transaction columns.
+    pub(super) fn resolve_headers(&self, headers: &ByteRecord) -> ByteRecord {
+        headers
+            .iter()
+            .map(|header| {
+                let header = String::from_utf8_lossy(header).trim().to_lowercase();
+                self.aliases
+                    .iter()
+                    .find(|(alias, _)| alias.trim().to_lowercase() == header)
+                    .map(|(_, column)| column.clone())
+                    .unwrap_or(header)
+            })
+            .collect()
+    }
+}
+
+/// A transaction decoder configured with the underline csv reader.
+#[derive(Debug)]
+pub struct CsvDecoder<R> {
+    reader: csv::Reader<R>,
+    dialect: CsvDialect,
+    headers: Option<ByteRecord>,
+    record: ByteRecord,
+    /// Whether the input is exhausted or failed.
+    finished: bool,
+}
+
+impl<R> CsvDecoder<R>
+where
+    R: io::Read,
+{
+    /// Creates new [`CsvDecoder`] with the underline reader and the default dialect.
+    pub fn new(reader: R) -> Self {
+        Self::with_dialect(reader, CsvDialect::default())
+    }
+
+    /// Creates new [`CsvDecoder`] with the underline reader and the given dialect.
+    pub fn with_dialect(reader: R, dialect: CsvDialect) -> Self {
+        let reader = ReaderBuilder::new()
+            .delimiter(dialect.delimiter)
+            .quote(dialect.quote)
+            .has_headers(dialect.has_headers)
+            .flexible(dialect.flexible)
+            .trim(Trim::All)
+            .from_reader(reader);
+
+        Self {
+            reader,
+            dialect,
+            headers: None,
+            record: ByteRecord::new(),
+            finished: false,
+        }
+    }
+
+    /// Returns the resolved header record.
+    fn headers(&mut self) -> Result<ByteRecord> {
+        if !self.dialect.has_headers {
+            return Ok(self.dialect.columns.iter().collect());
+        }
+        let headers = self.reader.byte_headers()?;
+        Ok(self.dialect.resolve_headers(headers))
+    }
+}
+
+impl<R> Decoder for CsvDecoder<R>
+where
+    R: io::Read,
+{
+    fn decode(&mut self) -> Option<Result<TransactionData>> {
+        if self.finished {
+            return None;
+        }
+        if self.headers.is_none() {
+            match self.headers() {
+                Ok(headers) => self.headers = Some(headers),
+                // Nothing can be decoded without the header record.
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(false) => {
+                self.finished = true;
+                None
+            }
+            Ok(true) => Some(
+                self.record
+                    .deserialize::<TransactionData>(self.headers.as_ref())
+                    .map_err(|err| self.malformed(err.into())),
+            ),
+            Err(err) if err.is_io_error() => {
+                self.finished = true;
+                Some(Err(err.into()))
+            }
+            Err(err) => Some(Err(self.malformed(err.into()))),
+        }
+    }
+}
+
+impl<R> CsvDecoder<R> {
+    /// Wraps the error with the position and the content of the current record.
+    fn malformed(&self, source: Error) -> Error {
+        let line = self
+            .record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or_default();
+        let delimiter = char::from(self.dialect.delimiter).to_string();
+        let record = self
+            .record
+            .iter()
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(&delimiter);
+        Error::MalformedRecord {
+            line,
+            record,
+            source: Box::new(source),
+        }
+    }
+}
+
+/// An account encoder configured with the underline csv writer.
+pub struct CsvEncoder<W: io::Write> {
+    /// The csv writer, taken when the encoder is finished.
+    writer: Option<csv::Writer<W>>,
+}
+
+impl<W> CsvEncoder<W>
+where
+    W: io::Write,
+{
+    /// Creates new [`CsvEncoder`] with the underline writer.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(WriterBuilder::new().from_writer(writer)),
+        }
+    }
+}
+
+impl<T, W> Encoder<T> for CsvEncoder<W>
+where
+    T: Serialize,
+    W: Finish,
+{
+    fn encode(&mut self, record: &T) -> Result<()> {
+        let writer = self.writer.as_mut().ok_or(Error::EncoderFinished)?;
+        writer.serialize(record).map_err(Error::from)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush().map_err(Error::IoError),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            let mut writer = writer
+                .into_inner()
+                .map_err(|err| Error::IoError(err.into_error()))?;
+            writer.finish().map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &str, dialect: CsvDialect) -> Vec<Result<TransactionData>> {
+        let mut decoder = CsvDecoder::with_dialect(input.as_bytes(), dialect);
+        std::iter::from_fn(|| decoder.decode()).collect()
+    }
+
+    #[test]
+    fn decode_semicolon_input_with_aliases_and_extra_columns() {
+        let input = "Kind;Customer;Reference;Amount;Note\ndeposit;1;1;1.5;first\n";
+        let dialect = CsvDialect {
+            delimiter: b';',
+            aliases: vec![
+                ("kind".into(), "type".into()),
+                ("customer".into(), "client".into()),
+                ("reference".into(), "tx".into()),
+            ],
+            ..CsvDialect::default()
+        };
+        let transactions = decode_all(input, dialect);
+        assert_eq!(transactions.len(), 1);
+        let transaction = transactions[0].as_ref().unwrap();
+        assert_eq!(*transaction.client.inner_ref(), 1);
+        assert_eq!(transaction.amount, Some("1.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn decode_headerless_tab_input_with_reordered_columns() {
+        let input = "1\t7\tdeposit\t2.0\n1\t8\twithdrawal\t1.0\n";
+        let dialect = CsvDialect {
+            delimiter: b'\t',
+            has_headers: false,
+            columns: ["client", "tx", "type", "amount"]
+                .map(String::from)
+                .to_vec(),
+            ..CsvDialect::default()
+        };
+        let transactions = decode_all(input, dialect);
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions.iter().all(Result::is_ok));
+        assert_eq!(*transactions[1].as_ref().unwrap().id.inner_ref(), 8);
+    }
+
+    #[test]
+    fn reject_uneven_records_when_not_flexible() {
+        let input = "type,client,tx,amount\ndispute,1,1\ndeposit,1,2,1.0\n";
+        let dialect = CsvDialect {
+            flexible: false,
+            ..CsvDialect::default()
+        };
+        let transactions = decode_all(input, dialect);
+        match &transactions[0] {
+            Err(Error::MalformedRecord { line, record, .. }) => {
+                assert_eq!(*line, 2);
+                assert_eq!(record, "dispute,1,1");
+            }
+            _ => panic!("uneven record should be rejected"),
+        }
+        assert!(transactions[1].is_ok());
+    }
+
+    /// A reader failing on every read.
+    struct Failing;
+
+    impl io::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("corrupt stream"))
+        }
+    }
+
+    #[test]
+    fn stop_after_an_io_error() {
+        for input in ["", "type,client,tx,amount\ndeposit,1,1,1\n"] {
+            let mut decoder = CsvDecoder::new(io::Read::chain(input.as_bytes(), Failing));
+            let results = std::iter::from_fn(|| decoder.decode())
+                .take(10)
+                .collect::<Vec<_>>();
+            assert!(results.len() <= 2, "decoder should stop, found {results:?}");
+            assert!(results
+                .last()
+                .is_some_and(|result| matches!(result, Err(err) if err.is_io_error())));
+        }
+    }
+}
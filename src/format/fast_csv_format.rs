@@ -0,0 +1,624 @@
+//! Fast CSV format.
+//!
+//! This module defines the [`FastCsvDecoder`], a CSV [`Decoder`] which parses the
+//! transaction columns straight from the bytes of the input buffer, without going
+//! through serde and without allocating per record. Records with quoted fields, or
+//! spanning two buffer fills, are split by the `csv` crate instead. The decoder
+//! accepts the same inputs and produces the same transactions as the
+//! [`CsvDecoder`](super::CsvDecoder).
+//!
+
+use std::fmt::{self, Write};
+use std::io::{self, BufRead, BufReader};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use csv::{ByteRecord, ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+
+use super::{CsvDialect, Decoder};
+use crate::error::Error;
+use crate::prelude::{TransactionData, TransactionType};
+use crate::Result;
+
+/// Size of the input buffer.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Number of transaction fields.
+const FIELDS: usize = 5;
+const TYPE: usize = 0;
+const CLIENT: usize = 1;
+const TX: usize = 2;
+const AMOUNT: usize = 3;
+const IDEMPOTENCY_KEY: usize = 4;
+
+/// Positions of the transaction fields in a record.
+#[derive(Debug)]
+struct Columns {
+    /// Transaction field of every record column, if any.
+    fields: Vec<Option<usize>>,
+    /// Number of columns of every record, unless the dialect is flexible.
+    expected: Option<usize>,
+}
+
+impl Columns {
+    /// Creates new [`Columns`] from the resolved header record.
+    fn new(headers: &ByteRecord) -> Self {
+        let fields = headers
+            .iter()
+            .map(|header| match header {
+                b"type" => Some(TYPE),
+                b"client" => Some(CLIENT),
+                b"tx" => Some(TX),
+                b"amount" => Some(AMOUNT),
+                b"idempotency_key" => Some(IDEMPOTENCY_KEY),
+                _ => None,
+            })
+            .collect();
+        Self {
+            fields,
+            expected: None,
+        }
+    }
+}
+
+/// A transaction decoder parsing CSV records without deserializing them.
+#[derive(Debug)]
+pub struct FastCsvDecoder<R> {
+    reader: R,
+    dialect: CsvDialect,
+    columns: Option<Columns>,
+    /// Record copied out of the input buffer when it cannot be parsed in place.
+    line: Vec<u8>,
+    record: ByteRecord,
+    line_number: u64,
+    /// Whether the input is exhausted or failed.
+    finished: bool,
+}
+
+impl<R> FastCsvDecoder<BufReader<R>>
+where
+    R: io::Read,
+{
+    /// Creates new [`FastCsvDecoder`] with the underline reader and the default dialect.
+    pub fn new(reader: R) -> Self {
+        Self::with_dialect(reader, CsvDialect::default())
+    }
+
+    /// Creates new [`FastCsvDecoder`] with the underline reader and the given dialect.
+    pub fn with_dialect(reader: R, dialect: CsvDialect) -> Self {
+        Self::from_buffered(BufReader::with_capacity(BUFFER_SIZE, reader), dialect)
+    }
+}
+
+impl<R> FastCsvDecoder<R>
+where
+    R: BufRead,
+{
+    /// Creates new [`FastCsvDecoder`] parsing the records straight from the buffer
+    /// of the reader, with the given dialect. Records are parsed in place as long
+    /// as they fit in the buffer, so a reader exposing the whole input at once,
+    /// such as a memory-mapped file, never copies a record.
+    pub fn from_buffered(reader: R, dialect: CsvDialect) -> Self {
+        Self {
+            reader,
+            dialect,
+            columns: None,
+            line: vec![],
+            record: ByteRecord::new(),
+            line_number: 0,
+            finished: false,
+        }
+    }
+
+    /// Returns the positions of the transaction fields, reading the header
+    /// record if needed. Returns `None` if the input is empty.
+    fn columns(&mut self) -> Result<Option<Columns>> {
+        let headers = if self.dialect.has_headers {
+            let line = self.line_number + 1;
+            match self.read_record() {
+                Ok(true) => self.dialect.resolve_headers(&self.record),
+                Ok(false) => return Ok(None),
+                Err(err @ Error::IoError(_)) => return Err(err),
+                Err(err) => return Err(self.malformed(line, err)),
+            }
+        } else {
+            self.dialect.columns.iter().collect()
+        };
+        let mut columns = Columns::new(&headers);
+        if self.dialect.has_headers {
+            columns.expected = Some(headers.len());
+        }
+        Ok(Some(columns))
+    }
+
+    /// Reads the next non empty record into `line`, along with the following
+    /// lines while a quoted field is open if the dialect allows quoted newlines.
+    /// Returns `false` at the end of the input.
+    fn read_line_record(&mut self) -> Result<bool> {
+        loop {
+            self.line.clear();
+            if self.read_line()? == 0 {
+                return Ok(false);
+            }
+            while self.dialect.quoted_newlines && count(&self.line, self.dialect.quote) % 2 == 1 {
+                if self.read_line()? == 0 {
+                    break;
+                }
+            }
+            if !strip_terminator(&self.line).is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Splits the record read into `line` into `record` with the `csv` crate.
+    fn split_record(&mut self) -> Result<()> {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(self.dialect.delimiter)
+            .quote(self.dialect.quote)
+            .has_headers(false)
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(self.line.as_slice());
+        self.record.clear();
+        reader.read_byte_record(&mut self.record)?;
+        Ok(())
+    }
+
+    /// Reads the next non empty record into `record`. Returns `false` at the
+    /// end of the input.
+    fn read_record(&mut self) -> Result<bool> {
+        if !self.read_line_record()? {
+            return Ok(false);
+        }
+        self.split_record()?;
+        Ok(true)
+    }
+
+    /// Appends the next line of the input to `line`.
+    fn read_line(&mut self) -> Result<usize> {
+        let read = self
+            .reader
+            .read_until(b'\n', &mut self.line)
+            .map_err(Error::IoError)?;
+        if read > 0 {
+            self.line_number += 1;
+        }
+        Ok(read)
+    }
+
+    /// Wraps the error with the line number and the content of `record`.
+    fn malformed(&self, line: u64, source: Error) -> Error {
+        malformed(line, self.record.iter(), self.dialect.delimiter, source)
+    }
+}
+
+impl<R> FastCsvDecoder<R>
+where
+    R: BufRead,
+{
+    /// Decodes the next transaction, reading the header record first if needed.
+    fn read_transaction(&mut self) -> Option<Result<TransactionData>> {
+        if self.columns.is_none() {
+            match self.columns() {
+                Ok(Some(columns)) => self.columns = Some(columns),
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        loop {
+            let Self {
+                reader,
+                dialect,
+                columns,
+                line_number,
+                ..
+            } = self;
+            let columns = columns.as_mut().expect("columns are resolved");
+            let buffer = match reader.fill_buf() {
+                Ok(buffer) => buffer,
+                Err(err) => return Some(Err(Error::IoError(err))),
+            };
+            if buffer.is_empty() {
+                return None;
+            }
+
+            // Fast path: the record is a complete unquoted line of the buffer.
+            if let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line = strip_terminator(&buffer[..end]);
+                if !line.contains(&dialect.quote) {
+                    *line_number += 1;
+                    if line.is_empty() {
+                        reader.consume(end + 1);
+                        continue;
+                    }
+                    let delimiter = dialect.delimiter;
+                    let fields = || line.split(move |byte| *byte == delimiter);
+                    let result = parse(fields(), columns, dialect.flexible)
+                        .map_err(|err| malformed(*line_number, fields(), delimiter, err));
+                    reader.consume(end + 1);
+                    return Some(result);
+                }
+            }
+
+            // Slow path: the record spans two buffer fills or has quoted fields.
+            let line = self.line_number + 1;
+            match self.read_line_record() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+            let flexible = self.dialect.flexible;
+            let delimiter = self.dialect.delimiter;
+            let columns = self.columns.as_mut().expect("columns are resolved");
+            let content = strip_terminator(&self.line);
+            if !content.contains(&self.dialect.quote) {
+                let fields = || content.split(move |byte| *byte == delimiter);
+                return Some(
+                    parse(fields(), columns, flexible)
+                        .map_err(|err| malformed(line, fields(), delimiter, err)),
+                );
+            }
+            return match self.split_record() {
+                Ok(()) => {
+                    let columns = self.columns.as_mut().expect("columns are resolved");
+                    Some(
+                        parse(self.record.iter(), columns, flexible)
+                            .map_err(|err| self.malformed(line, err)),
+                    )
+                }
+                Err(err) => Some(Err(self.malformed(line, err))),
+            };
+        }
+    }
+}
+
+impl<R> Decoder for FastCsvDecoder<R>
+where
+    R: BufRead,
+{
+    fn decode(&mut self) -> Option<Result<TransactionData>> {
+        if self.finished {
+            return None;
+        }
+        let result = self.read_transaction();
+        // Nothing can be decoded past a failed read or without the header record.
+        if matches!(result, None | Some(Err(Error::IoError(_)))) || self.columns.is_none() {
+            self.finished = true;
+        }
+        result
+    }
+}
+
+/// Parses the transaction from the record fields.
+fn parse<'a>(
+    fields: impl Iterator<Item = &'a [u8]>,
+    columns: &mut Columns,
+    flexible: bool,
+) -> Result<TransactionData> {
+    let mut values: [Option<&[u8]>; FIELDS] = [None; FIELDS];
+    let mut found = 0;
+    for (column, value) in fields.enumerate() {
+        found += 1;
+        if let Some(Some(field)) = columns.fields.get(column) {
+            values[*field] = Some(trim(value));
+        }
+    }
+    if !flexible {
+        let expected = *columns.expected.get_or_insert(found);
+        if found != expected {
+            return Err(Error::UnequalLengths { expected, found });
+        }
+    }
+
+    let tx_type = match values[TYPE] {
+        Some(b"deposit") => TransactionType::Deposit,
+        Some(b"withdrawal") => TransactionType::Withdrawal,
+        Some(b"dispute") => TransactionType::Dispute,
+        Some(b"resolve") => TransactionType::Resolve,
+        Some(b"chargeback") => TransactionType::ChargeBack,
+        _ => return Err(Error::InvalidField("type")),
+    };
+    let client = parse_id(values[CLIENT], "client", u16::from_str_radix)?;
+    let id = parse_id(values[TX], "tx", u32::from_str_radix)?;
+    let amount = match values[AMOUNT] {
+        None | Some(b"") => None,
+        Some(value) => Some(parse_amount(value).ok_or(Error::InvalidField("amount"))?),
+    };
+    let idempotency_key = match values[IDEMPOTENCY_KEY] {
+        None | Some(b"") => None,
+        Some(value) => Some(
+            String::from_utf8(value.to_vec())
+                .map_err(|_| Error::InvalidField("idempotency_key"))?,
+        ),
+    };
+    TransactionData::new(client, tx_type, id, amount, idempotency_key)
+}
+
+/// Parses a required identifier field, in decimal or in `0x` prefixed hexadecimal.
+fn parse_id<T: FromStr>(
+    value: Option<&[u8]>,
+    field: &'static str,
+    from_str_radix: fn(&str, u32) -> std::result::Result<T, ParseIntError>,
+) -> Result<T> {
+    let value = value.and_then(|value| std::str::from_utf8(value).ok());
+    let id = match value {
+        Some(value) => match value.strip_prefix("0x") {
+            Some(digits) => from_str_radix(digits, 16).ok(),
+            None => value.parse().ok(),
+        },
+        None => None,
+    };
+    id.ok_or(Error::InvalidField(field))
+}
+
+/// Parses the amount the way serde deserializes a CSV field into a [`Decimal`]:
+/// integers are taken as is, other numbers go through `f64` and its shortest
+/// representation, and the remaining values are parsed as decimals.
+fn parse_amount(value: &[u8]) -> Option<Decimal> {
+    let value = std::str::from_utf8(value).ok()?;
+    if is_short_decimal(value) {
+        // A decimal of at most 15 significant digits round trips through `f64`,
+        // which only drops its trailing fractional zeros.
+        return Decimal::from_str(value)
+            .ok()
+            .map(|amount| amount.normalize());
+    }
+    if let Ok(amount) = value.parse::<u64>() {
+        return Some(Decimal::from(amount));
+    }
+    if let Ok(amount) = value.parse::<i64>() {
+        return Some(Decimal::from(amount));
+    }
+    if value.parse::<u128>().is_ok() || value.parse::<i128>().is_ok() {
+        return None;
+    }
+    if let Ok(amount) = value.parse::<f64>() {
+        let mut buffer = FloatBuffer::default();
+        write!(buffer, "{amount}").ok()?;
+        return Decimal::from_str(buffer.as_str()).ok();
+    }
+    Decimal::from_str(value)
+        .or_else(|_| Decimal::from_scientific(value))
+        .ok()
+}
+
+/// Returns `true` if the value is an unsigned decimal with a fractional part
+/// and at most 15 digits.
+fn is_short_decimal(value: &str) -> bool {
+    let Some((integer, fraction)) = value.split_once('.') else {
+        return false;
+    };
+    let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    !integer.is_empty()
+        && !fraction.is_empty()
+        && integer.len() + fraction.len() <= 15
+        && digits(integer)
+        && digits(fraction)
+}
+
+/// Stack buffer holding the representation of a `f64`, which is at most a few
+/// hundred digits long.
+struct FloatBuffer {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+impl Default for FloatBuffer {
+    fn default() -> Self {
+        Self {
+            bytes: [0; 512],
+            len: 0,
+        }
+    }
+}
+
+impl FloatBuffer {
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for FloatBuffer {
+    fn write_str(&mut self, value: &str) -> fmt::Result {
+        let end = self.len + value.len();
+        let bytes = self.bytes.get_mut(self.len..end).ok_or(fmt::Error)?;
+        bytes.copy_from_slice(value.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Wraps the error with the line number and the trimmed record fields.
+fn malformed<'a>(
+    line: u64,
+    fields: impl Iterator<Item = &'a [u8]>,
+    delimiter: u8,
+    source: Error,
+) -> Error {
+    let delimiter = char::from(delimiter).to_string();
+    let record = fields
+        .map(|field| String::from_utf8_lossy(trim(field)))
+        .collect::<Vec<_>>()
+        .join(&delimiter);
+    Error::MalformedRecord {
+        line,
+        record,
+        source: Box::new(source),
+    }
+}
+
+/// Removes the trailing line terminator.
+fn strip_terminator(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Removes the leading and trailing ASCII whitespaces.
+fn trim(mut value: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = value {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        value = rest;
+    }
+    while let [rest @ .., last] = value {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        value = rest;
+    }
+    value
+}
+
+/// Returns the number of occurrences of the byte.
+fn count(line: &[u8], byte: u8) -> usize {
+    line.iter().filter(|b| **b == byte).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use quickcheck::{Arbitrary, Gen};
+    use quickcheck_macros::quickcheck;
+
+    use super::*;
+    use crate::format::CsvDecoder;
+
+    /// A CSV input mixing valid, invalid, quoted and blank records.
+    #[derive(Debug, Clone)]
+    struct Input(String);
+
+    impl Arbitrary for Input {
+        fn arbitrary(g: &mut Gen) -> Self {
+            const TYPES: [&str; 7] = [
+                "deposit",
+                "withdrawal",
+                "dispute",
+                "resolve",
+                "chargeback",
+                "\"deposit\"",
+                "refund",
+            ];
+            const AMOUNTS: [&str; 12] = [
+                "",
+                "1.5",
+                " 2.0 ",
+                "1e2",
+                "x",
+                "\"3,5\"",
+                "-4",
+                "0.12345678901234567890",
+                "007.2500",
+                "0.000",
+                "true",
+                "99999999999999999999",
+            ];
+            let mut input = String::from("type, client, tx, amount\n");
+            for _ in 0..usize::arbitrary(g) % 20 {
+                let record = match u8::arbitrary(g) % 8 {
+                    0 => String::new(),
+                    1 => format!("{}, {}", g.choose(&TYPES).unwrap(), u16::arbitrary(g)),
+                    _ => format!(
+                        "{},{}, {} ,{}",
+                        g.choose(&TYPES).unwrap(),
+                        u16::arbitrary(g),
+                        u32::arbitrary(g),
+                        g.choose(&AMOUNTS).unwrap()
+                    ),
+                };
+                input.push_str(&record);
+                input.push_str(if bool::arbitrary(g) { "\n" } else { "\r\n" });
+            }
+            Self(input)
+        }
+    }
+
+    fn summarize(decoder: &mut dyn Decoder) -> Vec<String> {
+        std::iter::from_fn(|| decoder.decode())
+            .map(|result| match result {
+                Ok(transaction) => format!("{transaction:?}"),
+                // The `csv` crate miscounts lines after blank CRLF lines.
+                Err(Error::MalformedRecord { .. }) => "malformed".to_string(),
+                Err(err) => panic!("unexpected error {err}"),
+            })
+            .collect()
+    }
+
+    #[quickcheck]
+    fn decode_like_the_csv_decoder(input: Input, flexible: bool) {
+        let dialect = CsvDialect {
+            flexible,
+            ..CsvDialect::default()
+        };
+        let expected = summarize(&mut CsvDecoder::with_dialect(
+            input.0.as_bytes(),
+            dialect.clone(),
+        ));
+        let decoded = summarize(&mut FastCsvDecoder::with_dialect(
+            input.0.as_bytes(),
+            dialect,
+        ));
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn report_malformed_record_lines() {
+        let input = "type,client,tx,amount\r\n\r\ndeposit, 1, x, 1.0\r\n\"withdrawal\",1\r\n";
+        let mut decoder = FastCsvDecoder::new(input.as_bytes());
+        let errors = std::iter::from_fn(|| decoder.decode())
+            .map(|result| match result {
+                Err(Error::MalformedRecord { line, record, .. }) => (line, record),
+                result => panic!("expected a malformed record error, found {result:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                (3, "deposit,1,x,1.0".to_string()),
+                (4, "withdrawal,1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_records_spanning_buffer_fills() {
+        let mut input = String::from("type,client,tx,amount,idempotency_key\n");
+        for tx in 0..10_000 {
+            input.push_str(&format!("deposit,{},{tx},1.25,key-{tx}\n", tx % 7));
+        }
+        let mut decoder = FastCsvDecoder::new(input.as_bytes());
+        let transactions = std::iter::from_fn(|| decoder.decode())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(transactions.len(), 10_000);
+        assert_eq!(*transactions[9_999].id.inner_ref(), 9_999);
+        assert_eq!(transactions[42].idempotency_key.as_deref(), Some("key-42"));
+    }
+
+    /// A reader failing on every read.
+    struct Failing;
+
+    impl io::Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("corrupt stream"))
+        }
+    }
+
+    #[test]
+    fn stop_after_an_io_error() {
+        for input in [
+            "",
+            "type,client,tx,amount\n",
+            "type,client,tx,amount\ndeposit,1,1,1\n",
+        ] {
+            let mut decoder = FastCsvDecoder::new(io::Read::chain(input.as_bytes(), Failing));
+            let results = std::iter::from_fn(|| decoder.decode())
+                .take(10)
+                .collect::<Vec<_>>();
+            assert!(results.len() <= 2, "decoder should stop, found {results:?}");
+            assert!(matches!(results.last(), Some(Err(Error::IoError(_)))));
+        }
+    }
+}
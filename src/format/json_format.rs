@@ -0,0 +1,142 @@
+//! JSON Lines format.
+//!
+//! This module defines the JSON Lines [`Decoder`] and [`Encoder`] implementations
+//! which read one transaction object and write one account object per line.
+//!
+
+use std::io::{self, BufRead, BufReader, Write};
+
+use serde::Serialize;
+
+use super::{Decoder, Encoder};
+use crate::compression::Finish;
+use crate::error::Error;
+use crate::prelude::TransactionData;
+use crate::Result;
+
+/// A transaction decoder reading one JSON object per line.
+#[derive(Debug)]
+pub struct JsonDecoder<R> {
+    reader: BufReader<R>,
+    line: String,
+    line_number: u64,
+}
+
+impl<R> JsonDecoder<R>
+where
+    R: io::Read,
+{
+    /// Creates new [`JsonDecoder`] with the underline reader.
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+}
+
+impl<R> Decoder for JsonDecoder<R>
+where
+    R: io::Read,
+{
+    fn decode(&mut self) -> Option<Result<TransactionData>> {
+        loop {
+            self.line.clear();
+            self.line_number += 1;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) if self.line.trim().is_empty() => continue,
+                Ok(_) => {
+                    return Some(serde_json::from_str(&self.line).map_err(|err| {
+                        Error::MalformedRecord {
+                            line: self.line_number,
+                            record: self.line.trim_end().to_string(),
+                            source: Box::new(err.into()),
+                        }
+                    }))
+                }
+                Err(err) => return Some(Err(Error::IoError(err))),
+            }
+        }
+    }
+}
+
+/// An account encoder writing one JSON object per line.
+pub struct JsonEncoder<W: io::Write> {
+    writer: io::BufWriter<W>,
+    finished: bool,
+}
+
+impl<W> JsonEncoder<W>
+where
+    W: io::Write,
+{
+    /// Creates new [`JsonEncoder`] with the underline writer.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: io::BufWriter::new(writer),
+            finished: false,
+        }
+    }
+}
+
+impl<T, W> Encoder<T> for JsonEncoder<W>
+where
+    T: Serialize,
+    W: Finish,
+{
+    fn encode(&mut self, record: &T) -> Result<()> {
+        if self.finished {
+            return Err(Error::EncoderFinished);
+        }
+        serde_json::to_writer(&mut self.writer, record)?;
+        self.writer.write_all(b"\n").map_err(Error::IoError)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(Error::IoError)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.finished = true;
+        self.writer.finish().map_err(Error::IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::TransactionType;
+
+    #[test]
+    fn decode_transaction_lines() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+
+{"type": "dispute", "client": 1, "tx": 1}
+{"type": "withdrawal", "client": 1, "tx": 2}
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.5}
+"#;
+        let mut decoder = JsonDecoder::new(input.as_bytes());
+
+        let deposit = decoder.decode().unwrap().unwrap();
+        assert!(matches!(deposit.tx_type, TransactionType::Deposit));
+        assert_eq!(deposit.amount, Some("1.5".parse().unwrap()));
+
+        let dispute = decoder.decode().unwrap().unwrap();
+        assert!(matches!(dispute.tx_type, TransactionType::Dispute));
+        assert!(dispute.amount.is_none());
+
+        assert!(
+            matches!(
+                decoder.decode().unwrap(),
+                Err(Error::MalformedRecord { line: 4, .. })
+            ),
+            "withdrawal without amount is invalid"
+        );
+
+        let withdrawal = decoder.decode().unwrap().unwrap();
+        assert_eq!(withdrawal.amount, Some("0.5".parse().unwrap()));
+        assert!(decoder.decode().is_none());
+    }
+}
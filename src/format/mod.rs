@@ -0,0 +1,161 @@
+//! Format module.
+//!
+//! This module defines the [`Decoder`] and [`Encoder`] traits which specify how
+//! transactions are read from an input and how records are written to a report,
+//! along with the [`Format`] type used to select an implementation.
+//!
+
+#[cfg(feature = "arrow")]
+mod arrow_format;
+mod csv_format;
+mod fast_csv_format;
+mod json_format;
+
+use std::io;
+use std::str::FromStr;
+
+#[cfg(feature = "arrow")]
+pub use arrow_format::{ArrowEncoder, Columnar};
+pub use csv_format::{CsvDecoder, CsvDialect, CsvEncoder, COLUMNS};
+pub use fast_csv_format::FastCsvDecoder;
+pub use json_format::{JsonDecoder, JsonEncoder};
+use serde::Serialize;
+
+use crate::compression::Finish;
+use crate::error::Error;
+use crate::prelude::{AccountSnapshot, Rejection, TransactionData};
+use crate::Result;
+
+/// The [`Decoder`] trait specifies the behavior for decoding transaction records.
+pub trait Decoder {
+    /// Decodes the next transaction record. Returns `None` at the end of the input.
+    fn decode(&mut self) -> Option<Result<TransactionData>>;
+}
+
+/// The [`Encoder`] trait specifies the behavior for encoding report records.
+pub trait Encoder<T> {
+    fn encode(&mut self, record: &T) -> Result<()>;
+    fn flush(&mut self) -> Result<()>;
+
+    /// Writes the end of the report and finishes the underline writer, after
+    /// which no record can be encoded.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// The [`Record`] trait is implemented by the report records every [`Format`] can encode.
+#[cfg(not(feature = "arrow"))]
+pub trait Record: Serialize + 'static {}
+
+/// The [`Record`] trait is implemented by the report records every [`Format`] can encode.
+#[cfg(feature = "arrow")]
+pub trait Record: Serialize + Columnar + Send + 'static {}
+
+impl Record for AccountSnapshot {}
+impl Record for Rejection {}
+
+impl<D: Decoder + ?Sized> Decoder for Box<D> {
+    fn decode(&mut self) -> Option<Result<TransactionData>> {
+        (**self).decode()
+    }
+}
+
+impl<T, E: Encoder<T> + ?Sized> Encoder<T> for Box<E> {
+    fn encode(&mut self, record: &T) -> Result<()> {
+        (**self).encode(record)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        (**self).finish()
+    }
+}
+
+/// [`Format`] represents the supported input and report formats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    JsonLines,
+    #[cfg(feature = "arrow")]
+    ArrowIpc,
+}
+
+impl Format {
+    /// Creates a transaction decoder for this format. The dialect only applies to CSV inputs.
+    ///
+    /// Fails if the format can only be used for reports.
+    pub fn decoder<R>(self, reader: R, dialect: &CsvDialect) -> Result<Box<dyn Decoder + Send>>
+    where
+        R: io::Read + Send + 'static,
+    {
+        match self {
+            Self::Csv => Ok(Box::new(FastCsvDecoder::with_dialect(
+                reader,
+                dialect.clone(),
+            ))),
+            Self::JsonLines => Ok(Box::new(JsonDecoder::new(reader))),
+            #[cfg(feature = "arrow")]
+            Self::ArrowIpc => Err(Error::UnsupportedInputFormat(self)),
+        }
+    }
+
+    /// Creates a transaction decoder for this format reading from an already
+    /// buffered reader. CSV records are parsed straight from the reader buffer.
+    pub fn buffered_decoder<R>(
+        self,
+        reader: R,
+        dialect: &CsvDialect,
+    ) -> Result<Box<dyn Decoder + Send>>
+    where
+        R: io::BufRead + Send + 'static,
+    {
+        match self {
+            Self::Csv => Ok(Box::new(FastCsvDecoder::from_buffered(
+                reader,
+                dialect.clone(),
+            ))),
+            _ => self.decoder(reader, dialect),
+        }
+    }
+
+    /// Returns the file extension of this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::JsonLines => "jsonl",
+            #[cfg(feature = "arrow")]
+            Self::ArrowIpc => "arrow",
+        }
+    }
+
+    /// Creates a report encoder for this format.
+    pub fn encoder<T, W>(self, writer: W) -> Box<dyn Encoder<T> + Send>
+    where
+        T: Record,
+        W: Finish + Send + 'static,
+    {
+        match self {
+            Self::Csv => Box::new(CsvEncoder::new(writer)),
+            Self::JsonLines => Box::new(JsonEncoder::new(writer)),
+            #[cfg(feature = "arrow")]
+            Self::ArrowIpc => Box::new(ArrowEncoder::new(writer)),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "csv" => Ok(Self::Csv),
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            #[cfg(feature = "arrow")]
+            "arrow" => Ok(Self::ArrowIpc),
+            _ => Err(Error::UnknownFormat(value.into())),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! HTTP API.
+//!
+//! This module defines the [`HttpServer`] type which exposes the engine over HTTP:
+//!
+//! - `POST /transactions` submits a JSON transaction and answers with its outcome.
+//! - `GET /accounts` returns every account snapshot.
+//! - `GET /accounts/{client}` returns the client account snapshot.
+//! - `GET /transactions/{tx}` returns the transaction dispute state. The lookup
+//!   needs a shared history index, and answers `501 Not Implemented` without one.
+//!
+//! Requests are handled by a fixed pool of worker threads. The query string is
+//! ignored.
+
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel;
+use serde::Serialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::error::Error;
+use crate::prelude::{AccountSnapshot, Client, SharedRegistry, TransactionData, TransactionId};
+use crate::server::Shutdown;
+use crate::transport::{Batch, Envelope, Outcome};
+use crate::Result;
+
+/// Interval at which the request loop checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Default number of threads handling requests.
+pub const DEFAULT_WORKERS: usize = 8;
+
+/// [`HttpServer`] type. See module level [documentation](self).
+pub struct HttpServer {
+    server: tiny_http::Server,
+    registry: SharedRegistry,
+    outgoing_transaction: channel::Sender<Batch>,
+    shutdown: Shutdown,
+    workers: usize,
+}
+
+impl HttpServer {
+    /// Creates new [`HttpServer`] accepting requests on the listener.
+    pub fn new(
+        listener: TcpListener,
+        registry: SharedRegistry,
+        outgoing_transaction: channel::Sender<Batch>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|err| Error::HttpError(err.to_string()))?;
+        Ok(Self {
+            server,
+            registry,
+            outgoing_transaction,
+            shutdown,
+            workers: DEFAULT_WORKERS,
+        })
+    }
+
+    /// Sets the number of threads handling requests.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Handles requests until the shutdown is triggered.
+    #[tracing::instrument(name = "Serve http requests", skip(self))]
+    pub fn serve(self) -> Result<()> {
+        thread::scope(|scope| {
+            let workers = (0..self.workers)
+                .map(|_| scope.spawn(|| self.work()))
+                .collect::<Vec<_>>();
+            let mut served = Ok(());
+            for worker in workers {
+                match worker.join() {
+                    Ok(Err(err)) => served = served.and(Err(err)),
+                    Err(err) => tracing::error!(err.cause_chain=?err),
+                    Ok(Ok(())) => {}
+                }
+            }
+            served
+        })
+    }
+
+    /// Handles requests on a worker thread until the shutdown is triggered.
+    fn work(&self) -> Result<()> {
+        while !self.shutdown.is_triggered() {
+            if let Some(request) = self
+                .server
+                .recv_timeout(POLL_INTERVAL)
+                .map_err(Error::IoError)?
+            {
+                handle_request(request, &self.registry, &self.outgoing_transaction);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Routes the request and sends the JSON response.
+fn handle_request(
+    mut request: Request,
+    registry: &SharedRegistry,
+    outgoing: &channel::Sender<Batch>,
+) {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    let (status, body) = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["transactions"]) => {
+            match serde_json::from_reader::<_, TransactionData>(request.as_reader()) {
+                Ok(transaction) => submit(transaction, outgoing),
+                Err(err) => (400, rejected(err.to_string())),
+            }
+        }
+        (Method::Get, ["accounts"]) => {
+            let accounts = registry
+                .read()
+                .iter()
+                .map(|account| account.map(|account| account.snapshot()))
+                .collect::<Result<Vec<AccountSnapshot>>>();
+            match accounts {
+                Ok(accounts) => (200, to_json(&accounts)),
+                Err(err) => internal_error(err),
+            }
+        }
+        (Method::Get, ["accounts", client]) => match client.parse() {
+            Ok(client) => match registry.read().get(&Client::from(client)) {
+                Ok(Some(account)) => (200, to_json(&account.snapshot())),
+                Ok(None) => (404, json!({"error": "unknown client"})),
+                Err(err) => internal_error(err),
+            },
+            Err(_) => (400, json!({"error": "invalid client id"})),
+        },
+        (Method::Get, ["transactions", tx]) => match tx.parse() {
+            Ok(_) if registry.read().history().is_none() => (
+                501,
+                json!({"error": "transaction lookup requires a history index"}),
+            ),
+            Ok(tx) => match registry.read().find_transaction(&TransactionId::from(tx)) {
+                Ok(Some(status)) => (200, to_json(&status)),
+                Ok(None) => (404, json!({"error": "unknown transaction"})),
+                Err(err) => internal_error(err),
+            },
+            Err(_) => (400, json!({"error": "invalid transaction id"})),
+        },
+        (_, ["transactions"] | ["accounts"] | ["accounts", _] | ["transactions", _]) => {
+            (405, json!({"error": "method not allowed"}))
+        }
+        _ => (404, json!({"error": "not found"})),
+    };
+
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(
+            Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                .expect("valid content type header"),
+        );
+    if let Err(err) = request.respond(response) {
+        tracing::error!(err.cause_chain=?err);
+    }
+}
+
+/// Submits the transaction to the pipeline and waits for its outcome.
+fn submit(
+    transaction: TransactionData,
+    outgoing: &channel::Sender<Batch>,
+) -> (u16, serde_json::Value) {
+    let (reply, outcome) = channel::bounded(1);
+    if outgoing
+        .send(vec![Envelope::with_reply(transaction, reply)])
+        .is_err()
+    {
+        return (503, json!({"error": "engine is shutting down"}));
+    }
+    match outcome.recv() {
+        Ok(Outcome::Applied) => (200, json!({"status": "applied"})),
+        Ok(Outcome::Rejected(reason)) => (422, rejected(reason)),
+        Err(_) => (503, json!({"error": "engine is shutting down"})),
+    }
+}
+
+/// Returns the rejection response body.
+fn rejected(reason: String) -> serde_json::Value {
+    json!({"status": "rejected", "reason": reason})
+}
+
+/// Logs the error and returns the internal error response.
+fn internal_error(err: Error) -> (u16, serde_json::Value) {
+    tracing::error!(err.cause_chain=?err);
+    (500, json!({"error": err.to_string()}))
+}
+
+/// Serializes the value into a JSON value.
+fn to_json(value: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_else(|err| json!({"error": err.to_string()}))
+}
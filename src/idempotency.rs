@@ -0,0 +1,291 @@
+//! Idempotency key store.
+//!
+//! This module defines the [`IdempotencyStore`] type which remembers the outcome of
+//! the transactions submitted with an idempotency key, so that a resubmitted
+//! transaction returns its original outcome instead of being applied twice. A key
+//! resubmitted with a different transaction is rejected.
+//!
+//! The store is bounded: once full, the oldest keys are evicted first. When opened
+//! from a file, every new key is appended and synced to the file before the outcome
+//! is acknowledged, and the store is restored from it on restart. The file is
+//! compacted when it grows past twice the store capacity.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::prelude::{Client, TransactionData, TransactionId, TransactionType};
+use crate::transport::Outcome;
+use crate::Result;
+
+/// The fields of the transaction submitted with a key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Payload {
+    client: Client,
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+}
+
+impl From<&TransactionData> for Payload {
+    fn from(transaction: &TransactionData) -> Self {
+        Self {
+            client: transaction.client.clone(),
+            tx_type: transaction.tx_type.clone(),
+            tx: transaction.id.clone(),
+            amount: transaction.amount,
+        }
+    }
+}
+
+/// The transaction and outcome recorded for a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    payload: Payload,
+    outcome: Outcome,
+}
+
+/// A persisted idempotency key entry.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    #[serde(flatten)]
+    record: Record,
+}
+
+/// The append only file backing a persisted store.
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    entries: usize,
+}
+
+/// [`IdempotencyStore`] type. See module level [documentation](self).
+#[derive(Debug)]
+pub struct IdempotencyStore {
+    capacity: usize,
+    records: HashMap<String, Record>,
+    keys: VecDeque<String>,
+    log: Option<Log>,
+}
+
+impl IdempotencyStore {
+    /// Creates new in-memory [`IdempotencyStore`] holding at most `capacity` keys.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            records: HashMap::new(),
+            keys: VecDeque::new(),
+            log: None,
+        }
+    }
+
+    /// Opens the [`IdempotencyStore`] persisted at `path`, creating the file if needed.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut store = Self::new(capacity);
+        let mut entries = 0;
+
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line.map_err(Error::IoError)?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let Entry { key, record } = serde_json::from_str(&line)?;
+                    store.remember(key, record);
+                    entries += 1;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::IoError(err)),
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(Error::IoError)?;
+        store.log = Some(Log {
+            path,
+            writer: BufWriter::new(file),
+            entries,
+        });
+        Ok(store)
+    }
+
+    /// Returns the outcome recorded for the key if any.
+    pub fn get(&self, key: &str) -> Option<&Outcome> {
+        self.records.get(key).map(|record| &record.outcome)
+    }
+
+    /// Returns the outcome recorded for the idempotency key of the transaction if any.
+    ///
+    /// Returns [`Error::IdempotencyKeyReused`] if the key was recorded with a
+    /// different transaction.
+    pub fn replay(&self, transaction: &TransactionData) -> Result<Option<&Outcome>> {
+        let record = match transaction.idempotency_key.as_ref() {
+            Some(key) => self.records.get(key),
+            None => None,
+        };
+        match record {
+            Some(record) if record.payload != Payload::from(transaction) => {
+                Err(Error::IdempotencyKeyReused)
+            }
+            record => Ok(record.map(|record| &record.outcome)),
+        }
+    }
+
+    /// Records the outcome of the transaction under its idempotency key, persisting
+    /// it if the store is backed by a file. A transaction without key is ignored.
+    pub fn insert(&mut self, transaction: &TransactionData, outcome: Outcome) -> Result<()> {
+        let key = match transaction.idempotency_key.as_ref() {
+            Some(key) if !self.records.contains_key(key) => key.clone(),
+            _ => return Ok(()),
+        };
+        let record = Record {
+            payload: Payload::from(transaction),
+            outcome,
+        };
+        if let Some(log) = self.log.as_mut() {
+            let entry = Entry {
+                key: key.clone(),
+                record: record.clone(),
+            };
+            serde_json::to_writer(&mut log.writer, &entry)?;
+            log.writer.write_all(b"\n").map_err(Error::IoError)?;
+            log.writer.flush().map_err(Error::IoError)?;
+            log.writer.get_ref().sync_data().map_err(Error::IoError)?;
+            log.entries += 1;
+        }
+        self.remember(key, record);
+
+        if matches!(&self.log, Some(log) if log.entries > 2 * self.capacity) {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns `true` if the store holds no key.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Records the entry in memory, evicting the oldest keys beyond the capacity.
+    fn remember(&mut self, key: String, record: Record) {
+        if self.records.insert(key.clone(), record).is_none() {
+            self.keys.push_back(key);
+        }
+        while self.keys.len() > self.capacity {
+            if let Some(key) = self.keys.pop_front() {
+                self.records.remove(&key);
+            }
+        }
+    }
+
+    /// Rewrites the backing file with the keys currently in the store.
+    fn compact(&mut self) -> Result<()> {
+        let log = match self.log.as_mut() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let compacted = log.path.with_extension("compact");
+        let mut writer = BufWriter::new(File::create(&compacted).map_err(Error::IoError)?);
+        for key in &self.keys {
+            let entry = Entry {
+                key: key.clone(),
+                record: self.records[key].clone(),
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n").map_err(Error::IoError)?;
+        }
+        writer.flush().map_err(Error::IoError)?;
+        writer.get_ref().sync_data().map_err(Error::IoError)?;
+        fs::rename(&compacted, &log.path).map_err(Error::IoError)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&log.path)
+            .map_err(Error::IoError)?;
+        log.writer = BufWriter::new(file);
+        log.entries = self.keys.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(reason: &str) -> Outcome {
+        Outcome::Rejected(reason.into())
+    }
+
+    fn deposit(key: &str, amount: i64) -> TransactionData {
+        let amount = Some(Decimal::from(amount));
+        TransactionData::new(1, TransactionType::Deposit, 1, amount, Some(key.into())).unwrap()
+    }
+
+    #[test]
+    fn evict_oldest_keys_beyond_capacity() {
+        let mut store = IdempotencyStore::new(2);
+        store.insert(&deposit("a", 1), Outcome::Applied).unwrap();
+        store.insert(&deposit("b", 1), rejected("b")).unwrap();
+        store.insert(&deposit("a", 1), rejected("ignored")).unwrap();
+        store.insert(&deposit("c", 1), Outcome::Applied).unwrap();
+
+        assert_eq!(store.len(), 2);
+        assert!(store.get("a").is_none(), "oldest key should be evicted");
+        assert_eq!(store.get("b"), Some(&rejected("b")));
+        assert_eq!(store.get("c"), Some(&Outcome::Applied));
+    }
+
+    #[test]
+    fn reject_key_reused_with_different_transaction() {
+        let mut store = IdempotencyStore::new(2);
+        store.insert(&deposit("a", 1), Outcome::Applied).unwrap();
+
+        let replayed = store.replay(&deposit("a", 1)).unwrap();
+        assert_eq!(replayed, Some(&Outcome::Applied));
+        assert_eq!(store.replay(&deposit("b", 1)).unwrap(), None);
+        assert!(matches!(
+            store.replay(&deposit("a", 2)),
+            Err(Error::IdempotencyKeyReused)
+        ));
+    }
+
+    #[test]
+    fn restore_keys_after_reopening_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.jsonl");
+
+        let mut store = IdempotencyStore::open(&path, 3).unwrap();
+        for key in ["a", "b", "c", "d", "e", "f", "g"] {
+            store.insert(&deposit(key, 1), rejected(key)).unwrap();
+        }
+        drop(store);
+
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= 6, "file should be compacted, found {lines} lines");
+
+        let store = IdempotencyStore::open(&path, 3).unwrap();
+        assert_eq!(store.len(), 3);
+        assert!(store.get("d").is_none());
+        for key in ["e", "f", "g"] {
+            assert_eq!(store.get(key), Some(&rejected(key)));
+        }
+    }
+}
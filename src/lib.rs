@@ -1,7 +1,15 @@
 pub mod client;
+pub mod compression;
 pub mod error;
+pub mod format;
+pub mod http;
+pub mod idempotency;
+pub mod metrics;
 pub mod prelude;
+pub mod prometheus;
 pub mod result;
+pub mod server;
+pub mod snapshot;
 pub mod transaction;
 pub mod transport;
 pub use result::Result;
@@ -0,0 +1,521 @@
+//! Pipeline metrics.
+//!
+//! This module defines the [`Metrics`] type which records the throughput and the
+//! backpressure of the transaction pipeline: the occupancy of the channel between
+//! the reader and the writer, the transactions moved by each side, the time each
+//! side spent blocked on the channel and the latency of every transaction
+//! operation. A writer often blocked on receive points to a slow reader, a reader
+//! often blocked on send to a slow writer or a channel too small for the bursts.
+//!
+//! The metrics also hold the account gauges, which the writer updates on every
+//! account change, so that they are read without scanning the accounts.
+//!
+//! [`Metrics`] is a cloneable handle updated with atomic counters, so it can be
+//! queried while the pipeline runs. [`Metrics::snapshot`] returns a serializable
+//! [`MetricsSnapshot`], which can be dumped at the end of a run.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::prelude::{AccountSnapshot, TransactionType};
+use crate::Result;
+
+/// Number of latency histogram buckets, one per power of two nanoseconds.
+const BUCKETS: usize = 65;
+
+/// Transaction operations, in the order of their latency histograms.
+const OPERATIONS: [TransactionType; 5] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::ChargeBack,
+];
+
+/// [`Metrics`] type. See module level [documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    epoch: Instant,
+    reader: Side,
+    writer: Side,
+    channel: Channel,
+    applied: [AtomicU64; OPERATIONS.len()],
+    /// Rejected transactions by operation and error kind.
+    rejected: Mutex<BTreeMap<(usize, &'static str), u64>>,
+    replayed: AtomicU64,
+    latencies: [Histogram; OPERATIONS.len()],
+    accounts: Mutex<AccountMetrics>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            reader: Side::default(),
+            writer: Side::default(),
+            channel: Channel::default(),
+            applied: Default::default(),
+            rejected: Mutex::default(),
+            replayed: AtomicU64::new(0),
+            latencies: Default::default(),
+            accounts: Mutex::default(),
+        }
+    }
+}
+
+/// Counters of one side of the channel.
+#[derive(Debug)]
+struct Side {
+    transactions: AtomicU64,
+    batches: AtomicU64,
+    blocked_nanos: AtomicU64,
+    /// First and last activity, in nanoseconds since the epoch.
+    started_nanos: AtomicU64,
+    last_nanos: AtomicU64,
+}
+
+impl Default for Side {
+    fn default() -> Self {
+        Self {
+            transactions: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+            blocked_nanos: AtomicU64::new(0),
+            started_nanos: AtomicU64::new(u64::MAX),
+            last_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Side {
+    fn start(&self, now: u64) {
+        self.started_nanos.fetch_min(now, Ordering::Relaxed);
+    }
+
+    fn record(&self, transactions: usize, blocked: Duration, now: u64) {
+        self.start(now);
+        self.transactions
+            .fetch_add(transactions as u64, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.blocked_nanos
+            .fetch_add(nanos(blocked), Ordering::Relaxed);
+        self.last_nanos.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> SideMetrics {
+        let transactions = self.transactions.load(Ordering::Relaxed);
+        let started = self.started_nanos.load(Ordering::Relaxed);
+        let active = self
+            .last_nanos
+            .load(Ordering::Relaxed)
+            .saturating_sub(started);
+        let transactions_per_sec = if active > 0 {
+            transactions as f64 / Duration::from_nanos(active).as_secs_f64()
+        } else {
+            0.0
+        };
+        SideMetrics {
+            transactions,
+            batches: self.batches.load(Ordering::Relaxed),
+            transactions_per_sec,
+            active_nanos: active,
+            blocked_nanos: self.blocked_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Occupancy of the channel, in batches, sampled on every send and receive.
+#[derive(Debug, Default)]
+struct Channel {
+    capacity: AtomicU64,
+    occupancy: AtomicU64,
+    max_occupancy: AtomicU64,
+    occupancy_sum: AtomicU64,
+    samples: AtomicU64,
+}
+
+impl Channel {
+    fn sample(&self, occupancy: usize, capacity: Option<usize>) {
+        let occupancy = occupancy as u64;
+        if let Some(capacity) = capacity {
+            self.capacity.store(capacity as u64, Ordering::Relaxed);
+        }
+        self.occupancy.store(occupancy, Ordering::Relaxed);
+        self.max_occupancy.fetch_max(occupancy, Ordering::Relaxed);
+        self.occupancy_sum.fetch_add(occupancy, Ordering::Relaxed);
+        self.samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ChannelMetrics {
+        let samples = self.samples.load(Ordering::Relaxed);
+        let mean_occupancy = if samples > 0 {
+            self.occupancy_sum.load(Ordering::Relaxed) as f64 / samples as f64
+        } else {
+            0.0
+        };
+        ChannelMetrics {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            occupancy: self.occupancy.load(Ordering::Relaxed),
+            max_occupancy: self.max_occupancy.load(Ordering::Relaxed),
+            mean_occupancy,
+        }
+    }
+}
+
+/// Latency histogram with a bucket per power of two nanoseconds.
+#[derive(Debug)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let nanos = nanos(latency);
+        let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Returns the upper bound of the bucket holding the quantile, capped by
+    /// the maximum recorded latency.
+    fn quantile(&self, counts: &[u64; BUCKETS], count: u64, quantile: f64) -> u64 {
+        let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, bucket_count) in counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                let upper = 1u64.checked_shl(bucket as u32).map_or(u64::MAX, |v| v - 1);
+                return upper.min(self.max_nanos.load(Ordering::Relaxed));
+            }
+        }
+        self.max_nanos.load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self) -> LatencyMetrics {
+        let counts = std::array::from_fn(|bucket| self.buckets[bucket].load(Ordering::Relaxed));
+        let count = counts.iter().sum();
+        if count == 0 {
+            return LatencyMetrics::default();
+        }
+        LatencyMetrics {
+            count,
+            sum_nanos: self.sum_nanos.load(Ordering::Relaxed),
+            mean_nanos: self.sum_nanos.load(Ordering::Relaxed) / count,
+            p50_nanos: self.quantile(&counts, count, 0.5),
+            p90_nanos: self.quantile(&counts, count, 0.9),
+            p99_nanos: self.quantile(&counts, count, 0.99),
+            max_nanos: self.max_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Metrics {
+    /// Creates new [`Metrics`] with all the counters at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current value of the metrics.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut transactions = OPERATIONS
+            .iter()
+            .zip(&self.0.applied)
+            .map(|(operation, applied)| {
+                let counts = OperationCounts {
+                    applied: applied.load(Ordering::Relaxed),
+                    rejected: BTreeMap::new(),
+                };
+                (operation.to_string(), counts)
+            })
+            .collect::<BTreeMap<_, _>>();
+        for ((index, kind), count) in self.0.rejected.lock().iter() {
+            let operation = OPERATIONS[*index].to_string();
+            if let Some(counts) = transactions.get_mut(&operation) {
+                counts.rejected.insert(kind.to_string(), *count);
+            }
+        }
+        let latencies = OPERATIONS
+            .iter()
+            .zip(&self.0.latencies)
+            .map(|(operation, histogram)| (operation.to_string(), histogram.snapshot()))
+            .collect();
+        MetricsSnapshot {
+            channel: self.0.channel.snapshot(),
+            reader: self.0.reader.snapshot(),
+            writer: self.0.writer.snapshot(),
+            applied: transactions.values().map(|counts| counts.applied).sum(),
+            rejected: transactions
+                .values()
+                .flat_map(|counts| counts.rejected.values())
+                .sum(),
+            replayed: self.0.replayed.load(Ordering::Relaxed),
+            transactions,
+            latencies,
+            accounts: self.0.accounts.lock().clone(),
+        }
+    }
+
+    /// Records the start of the reader.
+    pub(crate) fn start_reader(&self) {
+        self.0.reader.start(self.now());
+    }
+
+    /// Records a batch sent by the reader, the time it was blocked sending the
+    /// batch and the channel occupancy after the send.
+    pub(crate) fn record_send(
+        &self,
+        transactions: usize,
+        blocked: Duration,
+        occupancy: usize,
+        capacity: Option<usize>,
+    ) {
+        self.0.reader.record(transactions, blocked, self.now());
+        self.0.channel.sample(occupancy, capacity);
+    }
+
+    /// Records the start of the writer.
+    pub(crate) fn start_writer(&self) {
+        self.0.writer.start(self.now());
+    }
+
+    /// Records a batch received by the writer, the time it was blocked waiting
+    /// for the batch and the channel occupancy after the receive.
+    pub(crate) fn record_recv(&self, transactions: usize, blocked: Duration, occupancy: usize) {
+        self.0.writer.record(transactions, blocked, self.now());
+        self.0.channel.sample(occupancy, None);
+    }
+
+    /// Records the time spent waiting on an empty channel without receiving a batch.
+    pub(crate) fn record_idle(&self, blocked: Duration) {
+        self.0
+            .writer
+            .blocked_nanos
+            .fetch_add(nanos(blocked), Ordering::Relaxed);
+    }
+
+    /// Records the outcome of a transaction operation, with the error kind of
+    /// a rejected transaction.
+    pub(crate) fn record_outcome(
+        &self,
+        operation: &TransactionType,
+        rejection: Option<&'static str>,
+    ) {
+        let index = operation_index(operation);
+        match rejection {
+            None => {
+                self.0.applied[index].fetch_add(1, Ordering::Relaxed);
+            }
+            Some(kind) => *self.0.rejected.lock().entry((index, kind)).or_default() += 1,
+        }
+    }
+
+    /// Records a transaction whose outcome was replayed from the idempotency store.
+    pub(crate) fn record_replay(&self) {
+        self.0.replayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the latency of a transaction operation.
+    pub(crate) fn record_latency(&self, operation: &TransactionType, latency: Duration) {
+        self.0.latencies[operation_index(operation)].record(latency);
+    }
+
+    /// Records an account change in the account gauges, from the account
+    /// before the change, or `None` for a new account, to the account after it.
+    pub(crate) fn record_account(&self, before: Option<&AccountSnapshot>, after: &AccountSnapshot) {
+        let mut accounts = self.0.accounts.lock();
+        match before {
+            Some(before) => {
+                accounts.locked -= u64::from(before.locked);
+                accounts.held -= before.held;
+            }
+            None => accounts.accounts += 1,
+        }
+        accounts.locked += u64::from(after.locked);
+        accounts.held += after.held;
+    }
+
+    fn now(&self) -> u64 {
+        nanos(self.0.epoch.elapsed())
+    }
+}
+
+/// Point in time value of the [`Metrics`].
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub channel: ChannelMetrics,
+    pub reader: SideMetrics,
+    pub writer: SideMetrics,
+    /// Number of transactions applied.
+    pub applied: u64,
+    /// Number of transactions rejected.
+    pub rejected: u64,
+    /// Number of transactions whose outcome was replayed from the idempotency store.
+    pub replayed: u64,
+    /// Outcome of the transactions, by operation.
+    pub transactions: BTreeMap<String, OperationCounts>,
+    /// Latency of the transactions, by operation.
+    pub latencies: BTreeMap<String, LatencyMetrics>,
+    pub accounts: AccountMetrics,
+}
+
+impl MetricsSnapshot {
+    /// Writes the metrics as pretty printed JSON.
+    pub fn write_json(&self, mut writer: impl io::Write) -> Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+            .and_then(|()| writer.flush())
+            .map_err(Error::IoError)
+    }
+}
+
+/// Outcome of the transactions of an operation.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OperationCounts {
+    pub applied: u64,
+    /// Number of rejected transactions by error kind.
+    pub rejected: BTreeMap<String, u64>,
+}
+
+/// Account gauges across all the accounts.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountMetrics {
+    /// Number of accounts.
+    pub accounts: u64,
+    /// Number of locked accounts.
+    pub locked: u64,
+    /// Funds held by disputes.
+    pub held: Decimal,
+}
+
+/// Occupancy of the pipeline channel, in batches.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelMetrics {
+    pub capacity: u64,
+    /// Occupancy at the last send or receive.
+    pub occupancy: u64,
+    pub max_occupancy: u64,
+    pub mean_occupancy: f64,
+}
+
+/// Throughput of one side of the pipeline channel.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SideMetrics {
+    /// Number of transactions sent or received.
+    pub transactions: u64,
+    /// Number of batches sent or received.
+    pub batches: u64,
+    /// Transactions per second over the active time.
+    pub transactions_per_sec: f64,
+    /// Time between the start and the last batch.
+    pub active_nanos: u64,
+    /// Time spent blocked on a full channel when sending, or on an empty channel
+    /// when receiving.
+    pub blocked_nanos: u64,
+}
+
+/// Latency distribution of a transaction operation. Quantiles are rounded up to
+/// the next power of two nanoseconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LatencyMetrics {
+    pub count: u64,
+    pub sum_nanos: u64,
+    pub mean_nanos: u64,
+    pub p50_nanos: u64,
+    pub p90_nanos: u64,
+    pub p99_nanos: u64,
+    pub max_nanos: u64,
+}
+
+fn operation_index(operation: &TransactionType) -> usize {
+    OPERATIONS
+        .iter()
+        .position(|candidate| candidate == operation)
+        .expect("every operation is listed")
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_operation_outcomes_and_latencies() {
+        let metrics = Metrics::new();
+        for micros in 1..=100 {
+            let rejection = (micros % 10 == 0).then_some("invalid_transaction");
+            metrics.record_outcome(&TransactionType::Deposit, rejection);
+            metrics.record_latency(&TransactionType::Deposit, Duration::from_micros(micros));
+        }
+        metrics.record_outcome(&TransactionType::Withdrawal, Some("insufficient_funds"));
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.applied, snapshot.rejected), (90, 11));
+        assert_eq!(snapshot.transactions["deposit"].applied, 90);
+        assert_eq!(
+            snapshot.transactions["withdrawal"].rejected["insufficient_funds"],
+            1
+        );
+
+        let deposit = &snapshot.latencies["deposit"];
+        assert_eq!(deposit.count, 100);
+        assert_eq!(deposit.mean_nanos, 50_500);
+        assert_eq!(deposit.max_nanos, 100_000);
+        // Quantiles are bucket upper bounds, at most twice the exact value.
+        assert!((50_000..100_000).contains(&deposit.p50_nanos));
+        assert!((90_000..=100_000).contains(&deposit.p90_nanos));
+        assert_eq!(deposit.p99_nanos, 100_000);
+        assert_eq!(snapshot.latencies["dispute"].count, 0);
+    }
+
+    #[test]
+    fn record_channel_sides() {
+        let metrics = Metrics::new();
+        metrics.start_reader();
+        metrics.record_send(4, Duration::from_millis(2), 1, Some(8));
+        metrics.record_send(4, Duration::ZERO, 3, Some(8));
+        metrics.start_writer();
+        metrics.record_recv(4, Duration::ZERO, 2);
+        metrics.record_idle(Duration::from_millis(1));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(
+            (snapshot.reader.transactions, snapshot.reader.batches),
+            (8, 2)
+        );
+        assert_eq!(snapshot.reader.blocked_nanos, 2_000_000);
+        assert_eq!(
+            (snapshot.writer.transactions, snapshot.writer.batches),
+            (4, 1)
+        );
+        assert_eq!(snapshot.writer.blocked_nanos, 1_000_000);
+        assert_eq!(snapshot.channel.capacity, 8);
+        assert_eq!(snapshot.channel.occupancy, 2);
+        assert_eq!(snapshot.channel.max_occupancy, 3);
+        assert_eq!(snapshot.channel.mean_occupancy, 2.0);
+    }
+}
@@ -0,0 +1,273 @@
+//! Prometheus exporter.
+//!
+//! This module defines the [`MetricsServer`] type which exposes the engine metrics
+//! in the Prometheus text format on `GET /metrics`, for a long-running engine.
+//! The transaction counters and the pipeline metrics come from the [`Metrics`]
+//! recorded by the pipeline, along with the account gauges kept up to date by the
+//! writer. The channel depth is read from the channel.
+
+use std::fmt::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use crossbeam::channel;
+use tiny_http::{Header, Method, Response};
+
+use crate::error::Error;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::server::Shutdown;
+use crate::transport::Batch;
+use crate::Result;
+
+/// Interval at which the request loop checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Content type of the Prometheus text format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// [`MetricsServer`] type. See module level [documentation](self).
+pub struct MetricsServer {
+    server: tiny_http::Server,
+    metrics: Metrics,
+    /// Receiving end of the pipeline channel, only used to read its depth.
+    channel: channel::Receiver<Batch>,
+    shutdown: Shutdown,
+}
+
+impl MetricsServer {
+    /// Creates new [`MetricsServer`] accepting scrapes on the listener.
+    pub fn new(
+        listener: TcpListener,
+        metrics: Metrics,
+        channel: channel::Receiver<Batch>,
+        shutdown: Shutdown,
+    ) -> Result<Self> {
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|err| Error::HttpError(err.to_string()))?;
+        Ok(Self {
+            server,
+            metrics,
+            channel,
+            shutdown,
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers scrapes until the shutdown is triggered.
+    #[tracing::instrument(name = "Serve metrics", skip(self))]
+    pub fn serve(self) -> Result<()> {
+        while !self.shutdown.is_triggered() {
+            let Some(request) = self
+                .server
+                .recv_timeout(POLL_INTERVAL)
+                .map_err(Error::IoError)?
+            else {
+                continue;
+            };
+            let path = request.url().split('?').next().unwrap_or_default();
+            let response = match (request.method(), path) {
+                (Method::Get, "/metrics") => Response::from_string(self.render()).with_header(
+                    Header::from_bytes(&b"Content-Type"[..], CONTENT_TYPE.as_bytes())
+                        .expect("valid content type header"),
+                ),
+                (_, "/metrics") => {
+                    Response::from_string("method not allowed").with_status_code(405)
+                }
+                _ => Response::from_string("not found").with_status_code(404),
+            };
+            if let Err(err) = request.respond(response) {
+                tracing::error!(err.cause_chain=?err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the current metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render(
+            &mut out,
+            &self.metrics.snapshot(),
+            self.channel.len(),
+            self.channel.capacity(),
+        )
+        .expect("writing to a string cannot fail");
+        out
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a metric family.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) -> std::fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")
+}
+
+fn render(
+    out: &mut String,
+    metrics: &MetricsSnapshot,
+    depth: usize,
+    capacity: Option<usize>,
+) -> std::fmt::Result {
+    family(
+        out,
+        "payeng_transactions_applied_total",
+        "counter",
+        "Transactions applied, by type.",
+    )?;
+    for (operation, counts) in &metrics.transactions {
+        writeln!(
+            out,
+            "payeng_transactions_applied_total{{type=\"{operation}\"}} {}",
+            counts.applied
+        )?;
+    }
+    family(
+        out,
+        "payeng_transactions_rejected_total",
+        "counter",
+        "Transactions rejected, by type and reason.",
+    )?;
+    for (operation, counts) in &metrics.transactions {
+        for (reason, count) in &counts.rejected {
+            writeln!(
+                out,
+                "payeng_transactions_rejected_total{{type=\"{operation}\",reason=\"{reason}\"}} {count}"
+            )?;
+        }
+    }
+    family(
+        out,
+        "payeng_transactions_replayed_total",
+        "counter",
+        "Transactions whose outcome was replayed from the idempotency store.",
+    )?;
+    writeln!(
+        out,
+        "payeng_transactions_replayed_total {}",
+        metrics.replayed
+    )?;
+    family(
+        out,
+        "payeng_transaction_duration_seconds",
+        "summary",
+        "Time spent applying a transaction, by type.",
+    )?;
+    for (operation, latency) in &metrics.latencies {
+        for (quantile, nanos) in [
+            ("0.5", latency.p50_nanos),
+            ("0.9", latency.p90_nanos),
+            ("0.99", latency.p99_nanos),
+        ] {
+            writeln!(
+                out,
+                "payeng_transaction_duration_seconds{{type=\"{operation}\",quantile=\"{quantile}\"}} {}",
+                seconds(nanos)
+            )?;
+        }
+        writeln!(
+            out,
+            "payeng_transaction_duration_seconds_sum{{type=\"{operation}\"}} {}",
+            seconds(latency.sum_nanos)
+        )?;
+        writeln!(
+            out,
+            "payeng_transaction_duration_seconds_count{{type=\"{operation}\"}} {}",
+            latency.count
+        )?;
+    }
+
+    let accounts = &metrics.accounts;
+    family(out, "payeng_accounts", "gauge", "Accounts in the registry.")?;
+    writeln!(out, "payeng_accounts {}", accounts.accounts)?;
+    family(out, "payeng_accounts_locked", "gauge", "Locked accounts.")?;
+    writeln!(out, "payeng_accounts_locked {}", accounts.locked)?;
+    family(
+        out,
+        "payeng_held_funds",
+        "gauge",
+        "Funds held by disputes across all the accounts.",
+    )?;
+    writeln!(out, "payeng_held_funds {}", accounts.held.normalize())?;
+
+    family(
+        out,
+        "payeng_channel_depth",
+        "gauge",
+        "Batches waiting in the transaction channel.",
+    )?;
+    writeln!(out, "payeng_channel_depth {depth}")?;
+    if let Some(capacity) = capacity {
+        family(
+            out,
+            "payeng_channel_capacity",
+            "gauge",
+            "Capacity of the transaction channel, in batches.",
+        )?;
+        writeln!(out, "payeng_channel_capacity {capacity}")?;
+    }
+    for (side, metrics) in [("reader", &metrics.reader), ("writer", &metrics.writer)] {
+        let name = format!("payeng_{side}_transactions_total");
+        family(
+            out,
+            &name,
+            "counter",
+            "Transactions moved through the channel.",
+        )?;
+        writeln!(out, "{name} {}", metrics.transactions)?;
+        let name = format!("payeng_{side}_blocked_seconds_total");
+        family(out, &name, "counter", "Time spent blocked on the channel.")?;
+        writeln!(out, "{name} {}", seconds(metrics.blocked_nanos))?;
+    }
+    Ok(())
+}
+
+fn seconds(nanos: u64) -> f64 {
+    Duration::from_nanos(nanos).as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::prelude::{AccountSnapshot, Client, TransactionType};
+
+    #[test]
+    fn render_text_format() {
+        let metrics = Metrics::new();
+        metrics.record_outcome(&TransactionType::Deposit, None);
+        metrics.record_outcome(&TransactionType::Withdrawal, Some("insufficient_funds"));
+        metrics.record_latency(&TransactionType::Deposit, Duration::from_micros(3));
+        let account = |client: u16, held: Decimal, locked: bool| AccountSnapshot {
+            client: Client::from(client),
+            available: Decimal::ZERO,
+            held,
+            total: held,
+            locked,
+        };
+        let before = account(1, Decimal::ZERO, false);
+        metrics.record_account(None, &before);
+        metrics.record_account(Some(&before), &account(1, Decimal::new(15, 1), false));
+        metrics.record_account(None, &account(2, Decimal::ZERO, true));
+        let mut out = String::new();
+        render(&mut out, &metrics.snapshot(), 3, Some(8)).unwrap();
+
+        for line in [
+            "# TYPE payeng_transactions_applied_total counter",
+            "payeng_transactions_applied_total{type=\"deposit\"} 1",
+            "payeng_transactions_rejected_total{type=\"withdrawal\",reason=\"insufficient_funds\"} 1",
+            "payeng_transaction_duration_seconds_count{type=\"deposit\"} 1",
+            "payeng_accounts 2",
+            "payeng_accounts_locked 1",
+            "payeng_held_funds 1.5",
+            "payeng_channel_depth 3",
+            "payeng_channel_capacity 8",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing `{line}` in\n{out}");
+        }
+    }
+}
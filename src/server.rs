@@ -0,0 +1,166 @@
+//! TCP ingestion server.
+//!
+//! This module defines the [`Server`] type which accepts newline delimited
+//! transactions from many concurrent TCP connections, feeds them into the
+//! transaction pipeline and acknowledges each one with its [`Outcome`].
+//!
+//! Each transaction line is answered with an `applied` or a `rejected: <reason>` line.
+//! CSV connections send records without header, in the configured column order.
+
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use crate::error::Error;
+use crate::format::{CsvDialect, Format};
+use crate::transport::{Batch, Envelope, Outcome};
+use crate::Result;
+
+/// Interval at which the accept loop checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// [`Shutdown`] is a cloneable handle used to stop a running [`Server`].
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    /// Creates new [`Shutdown`] handle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the server to stop.
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the shutdown has been requested.
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Requests the server to stop whenever the process receives `SIGINT` or
+    /// `SIGTERM`. A second signal received before the server stopped terminates
+    /// the process.
+    pub fn on_termination(&self) -> Result<()> {
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register_conditional_shutdown(signal, 1, self.0.clone())
+                .and_then(|_| signal_hook::flag::register(signal, self.0.clone()))
+                .map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+/// [`Server`] type. See module level [documentation](self).
+pub struct Server {
+    listener: TcpListener,
+    format: Format,
+    dialect: CsvDialect,
+    outgoing_transaction: channel::Sender<Batch>,
+    shutdown: Shutdown,
+}
+
+impl Server {
+    /// Creates new [`Server`] accepting connections on the listener.
+    pub fn new(
+        listener: TcpListener,
+        format: Format,
+        dialect: CsvDialect,
+        outgoing_transaction: channel::Sender<Batch>,
+        shutdown: Shutdown,
+    ) -> Self {
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..dialect
+        };
+        Self {
+            listener,
+            format,
+            dialect,
+            outgoing_transaction,
+            shutdown,
+        }
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.listener.local_addr().map_err(Error::IoError)
+    }
+
+    /// Accepts connections until the shutdown is triggered. Open connections are
+    /// closed before returning.
+    #[tracing::instrument(name = "Serve connections", skip(self))]
+    pub fn serve(self) -> Result<()> {
+        self.listener
+            .set_nonblocking(true)
+            .map_err(Error::IoError)?;
+        let mut connections = vec![];
+
+        while !self.shutdown.is_triggered() {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    tracing::info!(%peer, "accepted connection");
+                    stream.set_nonblocking(false).map_err(Error::IoError)?;
+                    let handle = stream.try_clone().map_err(Error::IoError)?;
+                    let format = self.format;
+                    let dialect = self.dialect.clone();
+                    let outgoing = self.outgoing_transaction.clone();
+                    let thread = thread::spawn(move || {
+                        if let Err(err) = handle_connection(stream, format, dialect, outgoing) {
+                            tracing::error!(%peer, err.cause_chain=?err);
+                        }
+                    });
+                    connections.push((handle, thread));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(err) => tracing::error!(err.cause_chain=?err),
+            }
+            connections.retain(|(_, thread)| !thread.is_finished());
+        }
+
+        for (stream, thread) in connections {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            if let Err(err) = thread.join() {
+                tracing::error!(err.cause_chain=?err);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the connection transactions and acknowledges each of them.
+fn handle_connection(
+    stream: TcpStream,
+    format: Format,
+    dialect: CsvDialect,
+    outgoing: channel::Sender<Batch>,
+) -> Result<()> {
+    let mut acks = BufWriter::new(stream.try_clone().map_err(Error::IoError)?);
+    let mut decoder = format.decoder(stream, &dialect)?;
+
+    while let Some(result) = decoder.decode() {
+        let outcome = match result {
+            Ok(transaction) => {
+                let (reply, outcome) = channel::bounded(1);
+                outgoing
+                    .send(vec![Envelope::with_reply(transaction, reply)])
+                    .map_err(|e| Error::SendError(e.to_string()))?;
+                outcome.recv()?
+            }
+            Err(err @ Error::MalformedRecord { .. }) => Outcome::Rejected(err.to_string()),
+            Err(err) => return Err(err),
+        };
+        writeln!(acks, "{outcome}").map_err(Error::IoError)?;
+        acks.flush().map_err(Error::IoError)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Periodic account reports.
+//!
+//! This module defines the [`Snapshots`] type which writes the account report of a
+//! long-running engine to rotating files without stopping the ingestion. A snapshot
+//! is taken every N transactions, every T seconds or on demand through a
+//! [`SnapshotTrigger`], which can be bound to the `SIGUSR1` signal on Unix.
+//!
+//! Snapshots are written by the transaction writer between two transactions, so
+//! every account is reported in a consistent state. Each file is written aside and
+//! renamed once complete, so readers never observe a partial snapshot.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::compression::Compression;
+use crate::error::Error;
+use crate::format::Format;
+use crate::prelude::{AccountRegistry, ReportMode};
+use crate::Result;
+
+/// Prefix of the snapshot file names.
+const FILE_PREFIX: &str = "accounts-";
+
+/// [`SnapshotTrigger`] is a cloneable handle used to request a snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotTrigger(Arc<AtomicBool>);
+
+impl SnapshotTrigger {
+    /// Creates new [`SnapshotTrigger`] handle.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests a snapshot.
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Requests a snapshot whenever the process receives `SIGUSR1`.
+    #[cfg(unix)]
+    pub fn on_sigusr1(&self) -> Result<()> {
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, self.0.clone())
+            .map(|_| ())
+            .map_err(Error::IoError)
+    }
+
+    /// Returns `true` and clears the request if a snapshot has been requested.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// [`SnapshotPolicy`] specifies when snapshots are taken.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotPolicy {
+    /// Number of processed transactions between two snapshots.
+    pub every_transactions: Option<u64>,
+    /// Delay between two snapshots.
+    pub interval: Option<Duration>,
+    /// Number of snapshot files kept, the oldest files are removed first.
+    pub keep: Option<usize>,
+}
+
+/// [`Snapshots`] type. See module level [documentation](self).
+#[derive(Debug)]
+pub struct Snapshots {
+    directory: PathBuf,
+    format: Format,
+    compression: Compression,
+    policy: SnapshotPolicy,
+    trigger: SnapshotTrigger,
+    sequence: u64,
+    processed: u64,
+    last: Instant,
+}
+
+impl Snapshots {
+    /// Creates new [`Snapshots`] writing the reports into `directory`, creating
+    /// it if needed. Numbering resumes after the snapshots already present.
+    pub fn new(
+        directory: impl AsRef<Path>,
+        format: Format,
+        compression: Compression,
+        policy: SnapshotPolicy,
+    ) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory).map_err(Error::IoError)?;
+        let sequence = list_files(&directory)?
+            .last()
+            .map_or(0, |(sequence, _)| *sequence);
+        Ok(Self {
+            directory,
+            format,
+            compression,
+            policy,
+            trigger: SnapshotTrigger::new(),
+            sequence,
+            processed: 0,
+            last: Instant::now(),
+        })
+    }
+
+    /// Returns the handle used to request a snapshot.
+    pub fn trigger(&self) -> SnapshotTrigger {
+        self.trigger.clone()
+    }
+
+    /// Records a processed transaction.
+    pub(crate) fn record(&mut self) {
+        self.processed += 1;
+    }
+
+    /// Returns `true` if a snapshot is due according to the policy or has been requested.
+    pub(crate) fn is_due(&self) -> bool {
+        let by_count =
+            matches!(self.policy.every_transactions, Some(n) if self.processed >= n.max(1));
+        let by_time = matches!(self.policy.interval, Some(t) if self.last.elapsed() >= t);
+        by_count || by_time
+    }
+
+    /// Returns `true` if a snapshot is due or has been requested, clearing the
+    /// request. The caller then writes the snapshot with [`write`](Self::write).
+    pub(crate) fn take_due(&mut self) -> bool {
+        self.trigger.take() || self.is_due()
+    }
+
+    /// Writes a snapshot of the accounts selected by the mode into the next
+    /// rotating file and returns its path.
+    #[tracing::instrument(name = "Write snapshot", skip(self, registry))]
+    pub fn write(&mut self, registry: &AccountRegistry, mode: ReportMode) -> Result<PathBuf> {
+        self.sequence += 1;
+        self.processed = 0;
+        self.last = Instant::now();
+
+        let name = self.file_name(self.sequence);
+        let path = self.directory.join(&name);
+        let partial = self.directory.join(format!(".{name}.partial"));
+        let file = fs::File::create(&partial).map_err(Error::IoError)?;
+        let mut encoder = self
+            .format
+            .encoder(self.compression.encoder(std::io::BufWriter::new(file))?);
+        for account in registry.report(mode) {
+            encoder.encode(&account?.snapshot())?;
+        }
+        encoder.finish()?;
+        drop(encoder);
+        fs::rename(&partial, &path).map_err(Error::IoError)?;
+        tracing::info!(path = %path.display(), "wrote account snapshot");
+
+        self.rotate()?;
+        Ok(path)
+    }
+
+    /// Returns the file name of the snapshot with the given sequence number.
+    fn file_name(&self, sequence: u64) -> String {
+        let mut name = format!("{FILE_PREFIX}{sequence:06}.{}", self.format.extension());
+        if let Some(extension) = self.compression.extension() {
+            name = format!("{name}.{extension}");
+        }
+        name
+    }
+
+    /// Removes the oldest snapshot files beyond the number of files kept.
+    fn rotate(&self) -> Result<()> {
+        let Some(keep) = self.policy.keep else {
+            return Ok(());
+        };
+        let files = list_files(&self.directory)?;
+        let stale = files.len().saturating_sub(keep.max(1));
+        for (_, path) in &files[..stale] {
+            fs::remove_file(path).map_err(Error::IoError)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the snapshot files in the directory, ordered by sequence number.
+fn list_files(directory: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory).map_err(Error::IoError)? {
+        let path = entry.map_err(Error::IoError)?.path();
+        let sequence = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|name| name.split('.').next())
+            .and_then(|sequence| sequence.parse().ok());
+        if let Some(sequence) = sequence {
+            files.push((sequence, path));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{AccountManager, Client, TransactionData, TransactionId, TransactionType};
+
+    fn registry() -> AccountRegistry {
+        let mut registry = AccountRegistry::new();
+        let client = Client::from(1);
+        let deposit = TransactionData {
+            client: client.clone(),
+            tx_type: TransactionType::Deposit,
+            id: TransactionId::from(1),
+            amount: Some(2.into()),
+            idempotency_key: None,
+        };
+        registry
+            .get_mut_or_insert(client)
+            .unwrap()
+            .make_deposit(deposit)
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn take_snapshot_every_n_transactions() {
+        let policy = SnapshotPolicy {
+            every_transactions: Some(2),
+            ..SnapshotPolicy::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut snapshots =
+            Snapshots::new(dir.path(), Format::Csv, Compression::None, policy).unwrap();
+        let registry = registry();
+
+        snapshots.record();
+        assert!(!snapshots.take_due());
+        snapshots.record();
+        assert!(snapshots.take_due());
+        let path = snapshots.write(&registry, ReportMode::Full).unwrap();
+        assert_eq!(path, dir.path().join("accounts-000001.csv"));
+        assert!(fs::read_to_string(path).unwrap().contains("1,2"));
+        assert!(!snapshots.take_due());
+
+        snapshots.trigger().request();
+        assert!(snapshots.take_due());
+        assert!(!snapshots.take_due());
+        let path = snapshots.write(&registry, ReportMode::Full).unwrap();
+        assert_eq!(path, dir.path().join("accounts-000002.csv"));
+    }
+
+    #[test]
+    fn rotate_and_resume_snapshot_files() {
+        let policy = SnapshotPolicy {
+            keep: Some(2),
+            ..SnapshotPolicy::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let registry = registry();
+
+        let mut snapshots = Snapshots::new(
+            dir.path(),
+            Format::JsonLines,
+            Compression::Gzip,
+            policy.clone(),
+        )
+        .unwrap();
+        for _ in 0..3 {
+            snapshots.write(&registry, ReportMode::Full).unwrap();
+        }
+        let mut snapshots =
+            Snapshots::new(dir.path(), Format::JsonLines, Compression::Gzip, policy).unwrap();
+        snapshots.write(&registry, ReportMode::Full).unwrap();
+
+        let files = list_files(dir.path()).unwrap();
+        let names = files
+            .iter()
+            .map(|(_, path)| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["accounts-000003.jsonl.gz", "accounts-000004.jsonl.gz"]
+        );
+    }
+}
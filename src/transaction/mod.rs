@@ -4,13 +4,17 @@
 //! a transaction.
 //!
 
+mod parallel;
 mod pipeline;
+mod rejection;
 pub mod runtime;
 mod transaction_data;
 mod transaction_id;
 mod transaction_type;
 
+pub use parallel::ParallelReader;
 pub use pipeline::{Reader, Writer};
+pub use rejection::Rejection;
 pub use transaction_data::TransactionData;
 pub use transaction_id::TransactionId;
 pub use transaction_type::TransactionType;
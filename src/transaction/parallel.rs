@@ -0,0 +1,541 @@
+//! Parallel CSV reader.
+//!
+//! This module defines the [`ParallelReader`] which parses a large CSV file
+//! with several threads. The file is split into segments at record boundaries
+//! and a pool of workers decodes the segments concurrently. When the dialect
+//! allows quoted newlines, the file is scanned first to take quoted fields into
+//! account. Otherwise it is split at the first line break past every multiple
+//! of the segment size, without reading it. The decoded transactions are
+//! re-sequenced and sent in the input order, so the pipeline applies the same
+//! transactions in the same order as with a single-threaded
+//! [`Reader`](super::Reader), and writes the same reports.
+//!
+//! At most two segments per worker are decoded ahead of the segment being sent,
+//! which bounds the memory used by the reader.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, ScopedJoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel;
+
+use crate::error::Error;
+use crate::format::{CsvDialect, Decoder, FastCsvDecoder};
+use crate::metrics::Metrics;
+use crate::prelude::TransactionData;
+use crate::transport::{self, Batch, BatchSender, DEFAULT_BATCH_SIZE};
+use crate::Result;
+
+/// Default size of the segments decoded by the workers.
+const SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Size of the blocks read while scanning the file.
+const SCAN_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Interval at which the reader checks for panicked workers.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A byte range of the input starting at a record boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    start: u64,
+    end: u64,
+}
+
+/// The transactions decoded from a segment.
+#[derive(Debug)]
+struct Decoded {
+    transactions: Vec<Result<TransactionData>>,
+    /// Number of lines of the segment.
+    lines: u64,
+}
+
+/// The input split into segments.
+#[derive(Debug)]
+struct Layout {
+    /// Header record, prepended to every segment but the first one.
+    header: Arc<[u8]>,
+    /// Number of lines of the header record.
+    header_lines: u64,
+    segments: Vec<Segment>,
+}
+
+/// A transaction reader decoding a CSV file with several threads.
+#[derive(Debug)]
+pub struct ParallelReader {
+    path: PathBuf,
+    dialect: CsvDialect,
+    threads: usize,
+    outgoing_transaction: channel::Sender<Batch>,
+    strict: bool,
+    batch_size: usize,
+    segment_size: u64,
+    metrics: Option<Metrics>,
+}
+
+impl ParallelReader {
+    /// Creates new [`ParallelReader`] decoding the CSV file at `path` with
+    /// `threads` threads.
+    pub fn new(
+        path: impl AsRef<Path>,
+        dialect: CsvDialect,
+        threads: usize,
+        outgoing_transaction: channel::Sender<Batch>,
+    ) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            dialect,
+            threads: threads.max(1),
+            outgoing_transaction,
+            strict: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            segment_size: SEGMENT_SIZE,
+            metrics: None,
+        }
+    }
+
+    /// Sets the strict mode. In strict mode, the first malformed record aborts
+    /// the reader instead of being logged and skipped.
+    pub fn with_strict_mode(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the maximum number of transactions sent in a batch.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Sets the approximate size in bytes of the segments decoded by the workers.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+
+    /// Records the pipeline metrics of the reader.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Returns `true` if the dialect lets the file be decoded in segments. A
+    /// headerless input which is not flexible takes its number of fields from
+    /// its first record, which the segments do not see.
+    pub fn supports(dialect: &CsvDialect) -> bool {
+        dialect.has_headers || dialect.flexible
+    }
+
+    /// Splits the file into segments at record boundaries.
+    fn layout(&self) -> Result<Layout> {
+        let mut file = File::open(&self.path).map_err(Error::IoError)?;
+        let (segments, header_end, header_lines) = if self.dialect.quoted_newlines {
+            self.scan(&mut file)?
+        } else {
+            self.split(&mut file)?
+        };
+
+        let mut header = vec![];
+        if header_end > 0 {
+            file.seek(SeekFrom::Start(0)).map_err(Error::IoError)?;
+            (&mut file)
+                .take(header_end)
+                .read_to_end(&mut header)
+                .map_err(Error::IoError)?;
+        }
+        Ok(Layout {
+            header: header.into(),
+            header_lines,
+            segments,
+        })
+    }
+
+    /// Splits the file into segments by scanning it for the line breaks outside
+    /// quoted fields. Returns the segments, along with the end offset and the
+    /// number of lines of the header record.
+    fn scan(&self, file: &mut File) -> Result<(Vec<Segment>, u64, u64)> {
+        let mut block = vec![0; SCAN_BLOCK_SIZE];
+        let mut segments = vec![];
+        let mut start = 0;
+        let mut header_end = (!self.dialect.has_headers).then_some(0);
+        let mut header_lines = 0;
+        let mut offset = 0;
+        let mut lines = 0;
+        let mut quoted = false;
+        let mut blank = true;
+
+        loop {
+            let read = file.read(&mut block).map_err(Error::IoError)?;
+            if read == 0 {
+                break;
+            }
+            for (index, byte) in block[..read].iter().enumerate() {
+                if *byte == self.dialect.quote {
+                    quoted = !quoted;
+                }
+                if *byte != b'\n' {
+                    blank &= *byte == b'\r';
+                    continue;
+                }
+                lines += 1;
+                if quoted {
+                    continue;
+                }
+                let boundary = offset + index as u64 + 1;
+                match header_end {
+                    None if !blank => {
+                        header_end = Some(boundary);
+                        header_lines = lines;
+                    }
+                    Some(_) if boundary - start >= self.segment_size => {
+                        segments.push(Segment {
+                            start,
+                            end: boundary,
+                        });
+                        start = boundary;
+                    }
+                    _ => {}
+                }
+                blank = true;
+            }
+            offset += read as u64;
+        }
+        if start < offset {
+            segments.push(Segment { start, end: offset });
+        }
+        Ok((segments, header_end.unwrap_or_default(), header_lines))
+    }
+
+    /// Splits the file into segments of about the segment size, ending each one
+    /// at the first line break past its size. Only the header record and the
+    /// lines around the boundaries are read. Returns the segments, along with
+    /// the end offset and the number of lines of the header record.
+    fn split(&self, file: &mut File) -> Result<(Vec<Segment>, u64, u64)> {
+        let len = file.metadata().map_err(Error::IoError)?.len();
+        let mut reader = BufReader::new(file);
+        let mut line = vec![];
+        let mut header_end = 0;
+        let mut header_lines = 0;
+        if self.dialect.has_headers {
+            loop {
+                line.clear();
+                let read = reader
+                    .read_until(b'\n', &mut line)
+                    .map_err(Error::IoError)?;
+                if read == 0 {
+                    break;
+                }
+                header_end += read as u64;
+                header_lines += 1;
+                if line.iter().any(|byte| !matches!(byte, b'\r' | b'\n')) {
+                    break;
+                }
+            }
+        }
+
+        let mut segments = vec![];
+        let mut start = 0;
+        let mut target = header_end + self.segment_size;
+        while target < len {
+            reader
+                .seek(SeekFrom::Start(target - 1))
+                .map_err(Error::IoError)?;
+            line.clear();
+            let read = reader
+                .read_until(b'\n', &mut line)
+                .map_err(Error::IoError)?;
+            let end = target - 1 + read as u64;
+            if end >= len {
+                break;
+            }
+            segments.push(Segment { start, end });
+            start = end;
+            target = end + self.segment_size;
+        }
+        if start < len {
+            segments.push(Segment { start, end: len });
+        }
+        Ok((segments, header_end, header_lines))
+    }
+
+    /// Decodes the segment of the file. The line numbers of the errors are
+    /// relative to the segment, as if it followed the header record.
+    fn decode(&self, layout: &Layout, index: usize) -> Decoded {
+        let segment = &layout.segments[index];
+        let read = || -> Result<Vec<u8>> {
+            let mut file = File::open(&self.path).map_err(Error::IoError)?;
+            file.seek(SeekFrom::Start(segment.start))
+                .map_err(Error::IoError)?;
+            let mut range = Vec::with_capacity((segment.end - segment.start) as usize);
+            file.take(segment.end - segment.start)
+                .read_to_end(&mut range)
+                .map_err(Error::IoError)?;
+            Ok(range)
+        };
+        let range = match read() {
+            Ok(range) => range,
+            Err(err) => {
+                return Decoded {
+                    transactions: vec![Err(err)],
+                    lines: 0,
+                }
+            }
+        };
+        let lines = range.iter().filter(|byte| **byte == b'\n').count() as u64;
+        let range = io::Cursor::new(range);
+        let dialect = self.dialect.clone();
+        let mut decoder = if index == 0 {
+            FastCsvDecoder::with_dialect(Box::new(range) as Box<dyn Read>, dialect)
+        } else {
+            let header = io::Cursor::new(layout.header.clone());
+            FastCsvDecoder::with_dialect(Box::new(header.chain(range)) as Box<dyn Read>, dialect)
+        };
+
+        let mut transactions = vec![];
+        while let Some(result) = decoder.decode() {
+            let stop = matches!(result, Err(Error::IoError(_)));
+            transactions.push(result);
+            if stop {
+                break;
+            }
+        }
+        Decoded {
+            transactions,
+            lines,
+        }
+    }
+}
+
+/// Offsets the line number of a malformed record error by `lines`.
+fn relocate(err: Error, lines: u64) -> Error {
+    match err {
+        Error::MalformedRecord {
+            line,
+            record,
+            source,
+        } => Error::MalformedRecord {
+            line: line + lines,
+            record,
+            source,
+        },
+        err => err,
+    }
+}
+
+/// Receives the next result of the workers. Returns an error if a worker
+/// panicked, since its result would never be received.
+fn receive<T>(
+    results: &channel::Receiver<T>,
+    workers: &mut Vec<ScopedJoinHandle<'_, ()>>,
+) -> Result<T> {
+    loop {
+        match results.recv_timeout(POLL_INTERVAL) {
+            Ok(result) => return Ok(result),
+            Err(channel::RecvTimeoutError::Timeout) => {}
+            Err(channel::RecvTimeoutError::Disconnected) => {
+                return Err(Error::RecvError(channel::RecvError))
+            }
+        }
+        // The workers only return once the jobs are over.
+        if let Some(index) = workers.iter().position(|worker| worker.is_finished()) {
+            if let Err(err) = workers.swap_remove(index).join() {
+                tracing::error!(err.cause_chain=?err);
+            }
+            return Err(Error::WorkerPanicked);
+        }
+    }
+}
+
+impl transport::Sender for ParallelReader {
+    #[tracing::instrument(name = "send transaction in parallel", skip(self))]
+    fn send(&mut self) -> Result<()> {
+        let layout = self.layout()?;
+        let segments = layout.segments.len();
+        let window = 2 * self.threads;
+        let mut batches = BatchSender::new(self.outgoing_transaction.clone(), self.batch_size)
+            .with_metrics(self.metrics.clone());
+
+        thread::scope(|scope| {
+            let (jobs, pending_jobs) = channel::unbounded::<usize>();
+            let (decoded, results) = channel::unbounded();
+            let mut workers = (0..self.threads.min(segments))
+                .map(|_| {
+                    let (pending_jobs, decoded) = (pending_jobs.clone(), decoded.clone());
+                    let (reader, layout) = (&*self, &layout);
+                    scope.spawn(move || {
+                        for index in pending_jobs {
+                            if decoded.send((index, reader.decode(layout, index))).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(decoded);
+
+            let mut sent = || -> Result<()> {
+                let mut next_job = 0;
+                while next_job < segments.min(window) {
+                    let _ = jobs.send(next_job);
+                    next_job += 1;
+                }
+                let mut done = BTreeMap::new();
+                // Number of lines before the segment being sent.
+                let mut lines = 0;
+                for next in 0..segments {
+                    let decoded = loop {
+                        if let Some(decoded) = done.remove(&next) {
+                            break decoded;
+                        }
+                        let (index, decoded) = receive(&results, &mut workers)?;
+                        done.insert(index, decoded);
+                    };
+                    if next_job < segments {
+                        let _ = jobs.send(next_job);
+                        next_job += 1;
+                    }
+                    let Decoded {
+                        transactions,
+                        lines: segment_lines,
+                    } = decoded;
+                    let offset = if next == 0 {
+                        0
+                    } else {
+                        lines - layout.header_lines
+                    };
+                    for result in transactions {
+                        match result.map_err(|err| relocate(err, offset)) {
+                            Ok(data) => batches.push(data.into())?,
+                            // The pending batch is dropped so that nothing past the
+                            // last full batch is applied. An unreadable input
+                            // aborts the reader even in lenient mode.
+                            Err(err) if self.strict || err.is_io_error() => return Err(err),
+                            Err(err) => tracing::error!(err.cause_chain = ?err),
+                        }
+                    }
+                    lines += segment_lines;
+                }
+                Ok(())
+            };
+            let mut sent = sent();
+
+            drop((jobs, results));
+            for worker in workers {
+                if let Err(err) = worker.join() {
+                    tracing::error!(err.cause_chain=?err);
+                    sent = sent.and(Err(Error::WorkerPanicked));
+                }
+            }
+            sent
+        })?;
+
+        batches.flush()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::prelude::Reader;
+    use crate::transport::Sender;
+
+    fn input(quoted_newlines: bool) -> tempfile::NamedTempFile {
+        let amount = if quoted_newlines { "1\n.5" } else { "1.5" };
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "type, client, tx, amount").unwrap();
+        for tx in 1..=2_000 {
+            let client = tx % 13;
+            match tx % 10 {
+                0 => writeln!(file, "dispute, {client}, {},", tx - 9),
+                1 => writeln!(file, "\"deposit\", {client}, {tx}, \"{amount}\""),
+                2 => writeln!(file, "\r"),
+                3 => writeln!(file, "resolve, {client}, {}, ", tx - 13),
+                _ => writeln!(file, "deposit, {client}, {tx}, 2.5"),
+            }
+            .unwrap();
+        }
+        writeln!(file, "withdrawal, 1, oops, 1.0").unwrap();
+        file
+    }
+
+    fn collect(
+        sender: &mut dyn Sender,
+        receiver: channel::Receiver<Batch>,
+    ) -> (Vec<String>, Result<()>) {
+        let result = sender.send();
+        let transactions = receiver
+            .try_iter()
+            .flatten()
+            .map(|envelope| format!("{:?}", envelope.transaction))
+            .collect();
+        (transactions, result)
+    }
+
+    fn assert_same_as_sequential(dialect: CsvDialect) {
+        let input = input(dialect.quoted_newlines);
+        let (outgoing, incoming) = channel::unbounded();
+        let mut sequential = Reader::new(
+            FastCsvDecoder::with_dialect(File::open(input.path()).unwrap(), dialect.clone()),
+            outgoing,
+        )
+        .with_strict_mode(true);
+        let (expected, result) = collect(&mut sequential, incoming);
+        let Err(Error::MalformedRecord {
+            line: expected_line,
+            ..
+        }) = result
+        else {
+            panic!("expected a malformed record error, found {result:?}");
+        };
+
+        let (outgoing, incoming) = channel::unbounded();
+        let mut parallel = ParallelReader::new(input.path(), dialect, 4, outgoing)
+            .with_strict_mode(true)
+            .with_segment_size(1024);
+        assert!(parallel.layout().unwrap().segments.len() > 10);
+        let (transactions, result) = collect(&mut parallel, incoming);
+        let Err(Error::MalformedRecord { line, .. }) = result else {
+            panic!("expected a malformed record error, found {result:?}");
+        };
+        assert_eq!(line, expected_line);
+        assert_eq!(transactions.len(), expected.len());
+        assert_eq!(transactions, expected);
+    }
+
+    #[test]
+    fn decode_segments_in_input_order() {
+        assert_same_as_sequential(CsvDialect::default());
+    }
+
+    #[test]
+    fn decode_segments_split_at_offsets_in_input_order() {
+        assert_same_as_sequential(CsvDialect {
+            quoted_newlines: false,
+            ..CsvDialect::default()
+        });
+    }
+
+    #[test]
+    fn fail_when_a_worker_panics() {
+        let (decoded, results) = channel::unbounded::<()>();
+        thread::scope(|scope| {
+            let worker = decoded.clone();
+            let mut workers = vec![scope.spawn(move || {
+                let _decoded = worker;
+                panic!("worker failure");
+            })];
+            let received = receive(&results, &mut workers);
+            assert!(matches!(received, Err(Error::WorkerPanicked)));
+        });
+    }
+}
@@ -1,125 +1,329 @@
+use std::collections::BTreeSet;
 use std::io;
+use std::time::{Duration, Instant};
 
 use crossbeam::channel;
-use csv::{ByteRecord, ReaderBuilder, Trim, WriterBuilder};
+use rust_decimal::Decimal;
 
+use crate::compression::Finish;
 use crate::error::Error;
-use crate::prelude::{AccountManager, AccountRegistry, TransactionData};
-use crate::transport::{self, Receiver};
+use crate::format::{CsvDecoder, CsvDialect, CsvEncoder, Decoder, Encoder};
+use crate::idempotency::IdempotencyStore;
+use crate::metrics::Metrics;
+use crate::prelude::{
+    AccountEvent, AccountEventKind, AccountManager, AccountRegistry, AccountSnapshot, AccountStore,
+    Client, DisputeWindow, EventSink, Rejection, ReportMode, SharedHistory, SharedRegistry,
+    TransactionData, TransactionId,
+};
+use crate::snapshot::Snapshots;
+use crate::telemetry::CLIENT_TRACE_TARGET;
+use crate::transport::{self, Batch, BatchSender, Outcome, Receiver, DEFAULT_BATCH_SIZE};
 use crate::Result;
 
 use super::TransactionType;
-/// A transaction reader configured with the underline csv reader.
+
+/// Interval at which an idle writer checks whether a snapshot is due.
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A transaction reader configured with the underline decoder.
 ///
-/// We can only construct this struct using the [`from_reader`] method.
+/// We can construct this struct using the [`new`] or the [`from_reader`] method.
 #[derive(Debug)]
-pub struct Reader<R> {
-    reader: csv::Reader<R>,
-    outgoing_transaction: channel::Sender<TransactionData>,
+pub struct Reader<D> {
+    decoder: D,
+    outgoing_transaction: channel::Sender<Batch>,
+    strict: bool,
+    batch_size: usize,
+    metrics: Option<Metrics>,
 }
 
 /// A summary of transaction writer configured with the underline
-/// CSV writer.
-pub struct Writer<W: io::Write> {
-    writer: csv::Writer<W>,
-    registry: AccountRegistry,
-    incoming_transaction: channel::Receiver<TransactionData>,
+/// encoder.
+pub struct Writer<E> {
+    encoder: E,
+    registry: SharedRegistry,
+    incoming_transaction: channel::Receiver<Batch>,
+    rejections: Option<Box<dyn Encoder<Rejection> + Send>>,
+    idempotency: Option<IdempotencyStore>,
+    snapshots: Option<Snapshots>,
+    report_mode: ReportMode,
+    events: Option<Box<dyn EventSink>>,
+    metrics: Option<Metrics>,
+    traced_clients: BTreeSet<Client>,
 }
 
-impl<R> Reader<R>
+impl<D> Reader<D>
 where
-    R: io::Read,
+    D: Decoder,
 {
-    /// Creates new [`Reader`] with the underline reader.
-    pub fn from_reader(reader: R, outgoing_transaction: channel::Sender<TransactionData>) -> Self {
-        let reader = ReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .from_reader(reader);
-
+    /// Creates new [`Reader`] with the underline decoder.
+    pub fn new(decoder: D, outgoing_transaction: channel::Sender<Batch>) -> Self {
         Self {
-            reader,
+            decoder,
             outgoing_transaction,
+            strict: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            metrics: None,
         }
     }
+
+    /// Sets the strict mode. In strict mode, the first malformed record aborts
+    /// the reader instead of being logged and skipped.
+    pub fn with_strict_mode(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the maximum number of transactions sent in a batch.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Records the pipeline metrics of the reader.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
 }
 
-impl<R> transport::Sender for Reader<R>
+impl<R> Reader<CsvDecoder<R>>
 where
     R: io::Read,
+{
+    /// Creates new CSV [`Reader`] with the underline reader.
+    pub fn from_reader(reader: R, outgoing_transaction: channel::Sender<Batch>) -> Self {
+        Self::new(CsvDecoder::new(reader), outgoing_transaction)
+    }
+
+    /// Creates new CSV [`Reader`] with the underline reader and the given dialect.
+    pub fn with_dialect(
+        reader: R,
+        dialect: CsvDialect,
+        outgoing_transaction: channel::Sender<Batch>,
+    ) -> Self {
+        Self::new(
+            CsvDecoder::with_dialect(reader, dialect),
+            outgoing_transaction,
+        )
+    }
+}
+
+impl<D> transport::Sender for Reader<D>
+where
+    D: Decoder,
 {
     #[tracing::instrument(name = "send transaction", skip(self))]
     fn send(&mut self) -> Result<()> {
-        let headers = self.reader.byte_headers()?.clone();
-        let mut record = ByteRecord::new();
-
-        loop {
-            match self.reader.read_byte_record(&mut record) {
-                Ok(has_more) => {
-                    if !has_more {
-                        break;
-                    }
-                    match record.deserialize::<TransactionData>(Some(&headers)) {
-                        Ok(data) => {
-                            self.outgoing_transaction
-                                .send(data)
-                                .map_err(|e| Error::SendError(e.to_string()))?;
-                        }
-                        Err(err) => tracing::error!(err.cause_chain = ?err),
-                    };
-                }
+        let mut batches = BatchSender::new(self.outgoing_transaction.clone(), self.batch_size)
+            .with_metrics(self.metrics.clone());
+        while let Some(result) = self.decoder.decode() {
+            match result {
+                Ok(data) => batches.push(data.into())?,
+                // The pending batch is dropped so that nothing past the last
+                // full batch is applied. An unreadable input aborts the reader
+                // even in lenient mode.
+                Err(err) if self.strict || err.is_io_error() => return Err(err),
                 Err(err) => tracing::error!(err.cause_chain = ?err),
             }
         }
 
-        Ok(())
+        batches.flush()
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
     }
 }
 
-impl<W> Writer<W>
+impl<E> Writer<E>
 where
-    W: io::Write,
+    E: Encoder<AccountSnapshot>,
 {
-    /// Creates new [`Writer`].
-    #[tracing::instrument(name = "Create writer", skip(writer, incoming_transaction))]
-    pub fn from_writer(
-        writer: W,
-        incoming_transaction: channel::Receiver<TransactionData>,
-    ) -> Self {
-        let writer = WriterBuilder::new().from_writer(writer);
+    /// Creates new [`Writer`] with the underline encoder.
+    #[tracing::instrument(name = "Create writer", skip(encoder, incoming_transaction))]
+    pub fn new(encoder: E, incoming_transaction: channel::Receiver<Batch>) -> Self {
         Self {
-            writer,
+            encoder,
             incoming_transaction,
-            registry: AccountRegistry::default(),
+            registry: SharedRegistry::default(),
+            rejections: None,
+            idempotency: None,
+            snapshots: None,
+            report_mode: ReportMode::default(),
+            events: None,
+            metrics: None,
+            traced_clients: BTreeSet::new(),
         }
     }
 
-    #[tracing::instrument(name = "write account report", skip(self))]
-    pub fn write(&mut self) {
+    /// Records the rejected transactions with the given encoder.
+    pub fn with_rejections(mut self, rejections: Box<dyn Encoder<Rejection> + Send>) -> Self {
+        self.rejections = Some(rejections);
+        self
+    }
+
+    /// Deduplicates the transactions carrying an idempotency key with the given store.
+    pub fn with_idempotency(mut self, store: IdempotencyStore) -> Self {
+        self.idempotency = Some(store);
+        self
+    }
+
+    /// Writes periodic account snapshots while processing the transactions.
+    pub fn with_snapshots(mut self, snapshots: Snapshots) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// Sets the report mode. In delta mode, reports and snapshots only contain the
+    /// accounts changed since the previous report or snapshot.
+    pub fn with_report_mode(mut self, mode: ReportMode) -> Self {
+        self.report_mode = mode;
+        self
+    }
+
+    /// Publishes an event for every change applied to an account to the sink.
+    pub fn with_events(mut self, events: Box<dyn EventSink>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Records the pipeline metrics of the writer.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Logs every operation of the given clients with the account balances
+    /// before and after it, under the [`CLIENT_TRACE_TARGET`] target.
+    pub fn with_traced_clients(mut self, clients: impl IntoIterator<Item = Client>) -> Self {
+        self.traced_clients.extend(clients);
+        self
+    }
+
+    /// Keeps the accounts in the given store instead of the default in-memory store.
+    pub fn with_store(mut self, store: Box<dyn AccountStore>) -> Self {
+        let mut registry = AccountRegistry::with_store(store);
+        registry.set_dispute_window(self.registry.read().dispute_window());
+        if let Some(history) = self.registry.read().history() {
+            registry.set_history(history);
+        }
+        self.registry = SharedRegistry::new(registry.into());
+        self
+    }
+
+    /// Records the transaction histories in the shared index instead of the accounts.
+    pub fn with_history(self, history: SharedHistory) -> Self {
+        self.registry.write().set_history(history);
+        self
+    }
+
+    /// Bounds the transaction history kept for disputes with the given window.
+    pub fn with_dispute_window(self, window: DisputeWindow) -> Self {
+        self.registry.write().set_dispute_window(window);
+        self
+    }
+
+    /// Returns a handle to the account registry updated by this writer.
+    pub fn registry(&self) -> SharedRegistry {
+        self.registry.clone()
+    }
+
+    /// Processes all the incoming transactions then writes the reports.
+    pub fn write(&mut self) -> Result<()> {
         self.process_transaction();
-        for account in self.registry.iter() {
-            if let Err(err) = self.writer.serialize(&*account.inner_ref().lock()) {
+        self.report()
+    }
+
+    /// Writes the account report then finishes the report outputs. Returns the
+    /// first error preventing a complete report, every error is logged.
+    #[tracing::instrument(name = "write account report", skip(self))]
+    pub fn report(&mut self) -> Result<()> {
+        let mut reported = Ok(());
+        let mut registry = self.registry.write();
+        for account in registry.report(self.report_mode) {
+            if let Err(err) = account.and_then(|account| self.encoder.encode(&account.snapshot())) {
                 tracing::error!(err.cause_chain=?err);
+                reported = reported.and(Err(err));
             }
         }
+        registry.clear_changed();
+        if let Err(err) = registry.persist() {
+            tracing::error!(err.cause_chain=?err);
+        }
+        drop(registry);
+        if let Err(err) = self.encoder.finish() {
+            tracing::error!(err.cause_chain=?err);
+            reported = reported.and(Err(err));
+        }
+        if let Some(Err(err)) = self.events.as_mut().map(|events| events.flush()) {
+            tracing::error!(err.cause_chain=?err);
+        }
+        if let Some(Err(err)) = self
+            .rejections
+            .as_mut()
+            .map(|rejections| rejections.finish())
+        {
+            tracing::error!(err.cause_chain=?err);
+            reported = reported.and(Err(err));
+        }
+        reported
     }
 
     #[tracing::instrument(name = "Process transaction", skip(self))]
     pub fn process_transaction(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.start_writer();
+            for account in self.registry.read().iter() {
+                match account {
+                    Ok(account) => metrics.record_account(None, &account.snapshot()),
+                    Err(err) => tracing::error!(err.cause_chain=?err),
+                }
+            }
+        }
         loop {
-            match self.recv() {
-                Ok(data) => {
-                    let account = self.registry.get_mut_or_insert(data.client.clone());
-                    if let Err(err) = match data.tx_type {
-                        TransactionType::Deposit => account.make_deposit(data),
-                        TransactionType::Withdrawal => account.withdraw(data),
-                        TransactionType::Dispute => account.dispute(data.id),
-                        TransactionType::Resolve => account.resolve(data.id),
-                        TransactionType::ChargeBack => account.charge_back(data.id),
-                    } {
-                        tracing::error!(err.cause_chain=?err)
+            let waiting = self.metrics.is_some().then(Instant::now);
+            let batch = if self.snapshots.is_some() {
+                match self
+                    .incoming_transaction
+                    .recv_timeout(SNAPSHOT_POLL_INTERVAL)
+                {
+                    Ok(batch) => Ok(Some(batch)),
+                    Err(channel::RecvTimeoutError::Timeout) => Ok(None),
+                    Err(channel::RecvTimeoutError::Disconnected) => {
+                        Err(Error::RecvError(channel::RecvError))
+                    }
+                }
+            } else {
+                self.recv().map(Some)
+            };
+            if let (Some(metrics), Some(waiting)) = (&self.metrics, waiting) {
+                match &batch {
+                    Ok(Some(batch)) => metrics.record_recv(
+                        batch.len(),
+                        waiting.elapsed(),
+                        self.incoming_transaction.len(),
+                    ),
+                    _ => metrics.record_idle(waiting.elapsed()),
+                }
+            }
+            match batch {
+                Ok(Some(batch)) => {
+                    for envelope in batch {
+                        let started = self.metrics.is_some().then(Instant::now);
+                        let outcome = self.handle(&envelope.transaction);
+                        if let (Some(metrics), Some(started)) = (&self.metrics, started) {
+                            metrics
+                                .record_latency(&envelope.transaction.tx_type, started.elapsed());
+                        }
+                        envelope.acknowledge(outcome);
+                        if let Some(snapshots) = self.snapshots.as_mut() {
+                            snapshots.record();
+                        }
+                        self.snapshot();
                     }
                 }
+                Ok(None) => self.snapshot(),
                 Err(err) => {
                     tracing::error!(err.cause_chain=?err);
                     break;
@@ -127,14 +331,264 @@ where
             }
         }
     }
+
+    /// Writes an account snapshot if one is due.
+    fn snapshot(&mut self) {
+        let Some(snapshots) = self.snapshots.as_mut() else {
+            return;
+        };
+        // The registry is only locked when a snapshot is written.
+        if !snapshots.take_due() {
+            return;
+        }
+        let mut registry = self.registry.write();
+        match snapshots.write(&registry, self.report_mode) {
+            Ok(_) => registry.clear_changed(),
+            Err(err) => tracing::error!(err.cause_chain=?err),
+        }
+    }
+
+    /// Applies the transaction unless its idempotency key was already seen,
+    /// and returns the transaction outcome.
+    fn handle(&mut self, transaction: &TransactionData) -> Outcome {
+        let replayed = match self.idempotency.as_ref() {
+            Some(store) => store.replay(transaction).map(Option::<&Outcome>::cloned),
+            None => Ok(None),
+        };
+        if let Ok(Some(outcome)) = replayed {
+            tracing::info!(
+                idempotency_key = transaction.idempotency_key.as_deref(),
+                "replayed transaction outcome"
+            );
+            if let Some(metrics) = &self.metrics {
+                metrics.record_replay();
+            }
+            return outcome;
+        }
+
+        let applied = replayed.and_then(|_| self.apply(transaction.clone()));
+        if let Some(metrics) = &self.metrics {
+            let rejection = applied.as_ref().err().map(Error::kind);
+            metrics.record_outcome(&transaction.tx_type, rejection);
+        }
+        let outcome = match applied {
+            Ok(()) => Outcome::Applied,
+            Err(err) => {
+                tracing::error!(err.cause_chain=?err);
+                let outcome = Outcome::Rejected(err.to_string());
+                self.reject(transaction.clone(), &err);
+                outcome
+            }
+        };
+
+        if let Some(store) = self.idempotency.as_mut() {
+            if let Err(err) = store.insert(transaction, outcome.clone()) {
+                tracing::error!(err.cause_chain=?err);
+            }
+        }
+        outcome
+    }
+
+    /// Applies the transaction to the client account.
+    fn apply(&mut self, data: TransactionData) -> Result<()> {
+        let client = data.client.clone();
+        let id = data.id.clone();
+        let tx_type = data.tx_type.clone();
+        let kind = AccountEventKind::from(&data.tx_type);
+        let amount = data.amount;
+
+        let mut registry = self.registry.write();
+        let expired = registry.is_expired(&id);
+        let inserted = !registry.contains(&client);
+        let account = registry.get_mut_or_insert(client.clone())?;
+        let before = account.snapshot();
+        let disputable = matches!(
+            data.tx_type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        );
+        let applied = match data.tx_type {
+            TransactionType::Deposit => account.make_deposit(data),
+            TransactionType::Withdrawal => account.withdraw(data),
+            TransactionType::Dispute => account.dispute(data.id),
+            TransactionType::Resolve => account.resolve(data.id),
+            TransactionType::ChargeBack => account.charge_back(data.id),
+        };
+        let applied = match applied {
+            Err(Error::DisputeStateError)
+                if expired && account.transaction_status(&id).is_none() =>
+            {
+                Err(Error::DisputeWindowExpired)
+            }
+            applied => applied,
+        };
+        let after = account.snapshot();
+        if self.traced_clients.contains(&client) {
+            trace_operation(&tx_type, &id, amount, &before, &after, &applied);
+        }
+        if let Some(metrics) = &self.metrics {
+            if inserted {
+                metrics.record_account(None, &after);
+            } else if after != before {
+                metrics.record_account(Some(&before), &after);
+            }
+        }
+        applied?;
+
+        if let Some(events) = self.events.as_mut() {
+            let amount = amount.or_else(|| account.transaction_status(&id).map(|tx| tx.amount));
+            let mut published = vec![AccountEvent::new(kind, id.clone(), amount, after.clone())];
+            if after.locked && !before.locked {
+                published.push(AccountEvent::new(
+                    AccountEventKind::AccountLocked,
+                    id.clone(),
+                    None,
+                    after.clone(),
+                ));
+            }
+            for event in &published {
+                if let Err(err) = events.publish(event) {
+                    tracing::error!(err.cause_chain=?err);
+                }
+            }
+        }
+        if disputable {
+            if let Err(err) = registry.record_transaction(&client, id) {
+                tracing::error!(err.cause_chain=?err);
+            }
+        }
+        if after != before {
+            registry.mark_changed(client);
+        }
+        Ok(())
+    }
+
+    /// Records the rejected transaction if a rejection encoder is configured.
+    fn reject(&mut self, transaction: TransactionData, err: &Error) {
+        if let Some(rejections) = self.rejections.as_mut() {
+            if let Err(err) = rejections.encode(&Rejection::new(transaction, err)) {
+                tracing::error!(err.cause_chain=?err);
+            }
+        }
+    }
+}
+
+/// Logs an operation of a traced client with the balances before and after it.
+fn trace_operation(
+    tx_type: &TransactionType,
+    id: &TransactionId,
+    amount: Option<Decimal>,
+    before: &AccountSnapshot,
+    after: &AccountSnapshot,
+    applied: &Result<()>,
+) {
+    let outcome = match applied {
+        Ok(()) => "applied",
+        Err(err) => err.kind(),
+    };
+    tracing::info!(
+        target: CLIENT_TRACE_TARGET,
+        client = after.client.0,
+        tx = id.inner_ref(),
+        r#type = %tx_type,
+        amount = amount.map(tracing::field::display),
+        outcome,
+        available.before = %before.available,
+        available.after = %after.available,
+        held.before = %before.held,
+        held.after = %after.held,
+        total.before = %before.total,
+        total.after = %after.total,
+        locked.before = before.locked,
+        locked.after = after.locked,
+        "traced client operation"
+    );
+}
+
+impl<W> Writer<CsvEncoder<W>>
+where
+    W: Finish,
+{
+    /// Creates new CSV [`Writer`] with the underline writer.
+    pub fn from_writer(writer: W, incoming_transaction: channel::Receiver<Batch>) -> Self {
+        Self::new(CsvEncoder::new(writer), incoming_transaction)
+    }
 }
 
-impl<W> transport::Receiver for Writer<W>
+impl<E> transport::Receiver for Writer<E>
 where
-    W: io::Write,
+    E: Encoder<AccountSnapshot>,
 {
-    #[tracing::instrument(name = "Receive transaction", skip(self))]
-    fn recv(&mut self) -> Result<TransactionData> {
+    #[cfg_attr(
+        feature = "transaction-spans",
+        tracing::instrument(name = "Receive transaction", skip(self))
+    )]
+    fn recv(&mut self) -> Result<Batch> {
         self.incoming_transaction.recv().map_err(Error::RecvError)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::{fmt, EnvFilter, Registry};
+
+    use super::*;
+    use crate::transport::Sender;
+
+    /// Log sink shared with the test.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_selected_clients() {
+        let input = "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,3.0
+withdrawal,1,3,9.0
+withdrawal,2,4,1.0
+";
+        let logs = Logs::default();
+        let sink = logs.clone();
+        let subscriber = Registry::default()
+            .with(EnvFilter::new(format!("{CLIENT_TRACE_TARGET}=info")))
+            .with(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(move || sink.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let (outgoing, incoming) = channel::unbounded();
+            Reader::from_reader(input.as_bytes(), outgoing)
+                .send()
+                .unwrap();
+            Writer::from_writer(io::sink(), incoming)
+                .with_traced_clients([Client::from(1)])
+                .write()
+                .unwrap();
+        });
+
+        let logs = String::from_utf8(logs.0.lock().clone()).unwrap();
+        let lines: Vec<_> = logs.lines().collect();
+        assert_eq!(lines.len(), 2, "{logs}");
+        assert!(lines.iter().all(|line| line.contains("client=1")));
+        assert!(lines[0].contains("type=deposit"));
+        assert!(lines[0].contains("available.before=0 available.after=5"));
+        assert!(lines[1].contains("outcome=\"insufficient_funds\""));
+        assert!(lines[1].contains("available.before=5 available.after=5"));
+    }
+}
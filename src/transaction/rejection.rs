@@ -0,0 +1,43 @@
+//! Transaction rejection type.
+//!
+//! This module defines the [`Rejection`] type which records a transaction
+//! the engine refused to apply.
+//!
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::{TransactionData, TransactionId, TransactionType};
+use crate::error::Error;
+use crate::prelude::Client;
+
+/// [`Rejection`] represents a rejected transaction along with the rejection reason.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rejection {
+    pub client: Client,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub tx: TransactionId,
+    pub amount: Option<Decimal>,
+    pub reason: String,
+}
+
+impl Rejection {
+    /// Creates new [`Rejection`] for the transaction and the error which caused it.
+    pub fn new(transaction: TransactionData, error: &Error) -> Self {
+        let TransactionData {
+            client,
+            tx_type,
+            id,
+            amount,
+            ..
+        } = transaction;
+        Self {
+            client,
+            tx_type,
+            tx: id,
+            amount,
+            reason: error.to_string(),
+        }
+    }
+}
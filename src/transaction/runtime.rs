@@ -1,48 +1,416 @@
 //! Transaction processor.
 //!
 
+use std::fmt;
 use std::fs::File;
 use std::io;
+use std::net::TcpListener;
 use std::path::Path;
 use std::thread;
 
 use crossbeam::channel;
+use memmap2::Mmap;
 
-use super::{Reader, Writer};
+use super::{ParallelReader, Reader, Writer};
+use crate::compression::{self, Compression, Finish};
 use crate::error::Error;
-use crate::transport::Sender;
+use crate::format::{CsvDialect, Decoder, Encoder, Format};
+use crate::http::HttpServer;
+use crate::idempotency::IdempotencyStore;
+use crate::metrics::Metrics;
+use crate::prelude::{
+    AccountSnapshot, AccountStore, Client, DisputeWindow, EventSink, ReportMode, SharedHistory,
+};
+use crate::prometheus::MetricsServer;
+use crate::server::{Server, Shutdown};
+use crate::snapshot::Snapshots;
+use crate::transport::{channel_capacity, Batch, Sender, DEFAULT_BATCH_SIZE};
 use crate::Result;
 
+/// Path used to designate the standard input or output.
+pub const STDIO_PATH: &str = "-";
+
+/// Runtime configuration.
+pub struct Config {
+    /// Maximum number of transactions in flight in the transaction channel.
+    pub capacity: usize,
+    /// Maximum number of transactions the reader sends in a batch.
+    pub batch_size: usize,
+    /// Number of threads parsing an uncompressed CSV input file.
+    pub parse_threads: usize,
+    /// Memory-map an uncompressed input file instead of reading it.
+    pub mmap: bool,
+    /// Metrics recorded by the pipeline.
+    pub metrics: Option<Metrics>,
+    /// Format of the transaction input.
+    pub input_format: Format,
+    /// Dialect of the CSV transaction input.
+    pub dialect: CsvDialect,
+    /// Abort on the first malformed record without writing the account report.
+    /// The transactions read since the last full batch are not applied.
+    pub strict: bool,
+    /// Format of the account and rejection reports.
+    pub output_format: Format,
+    /// Output of the rejected transactions report.
+    pub rejections: Option<Box<dyn Finish + Send>>,
+    /// Store deduplicating the transactions carrying an idempotency key.
+    pub idempotency: Option<IdempotencyStore>,
+    /// Periodic account snapshots written while processing the transactions.
+    pub snapshots: Option<Snapshots>,
+    /// Accounts included in the report and the snapshots.
+    pub report_mode: ReportMode,
+    /// Sink receiving an event for every change applied to an account.
+    pub events: Option<Box<dyn EventSink>>,
+    /// Store keeping the accounts, in memory by default.
+    pub store: Option<Box<dyn AccountStore>>,
+    /// Window bounding the transaction history kept for disputes.
+    pub dispute_window: DisputeWindow,
+    /// Index keeping the transaction histories, inside the accounts by default.
+    pub history: Option<SharedHistory>,
+    /// Clients whose operations are logged with their balances.
+    pub traced_clients: Vec<Client>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("capacity", &self.capacity)
+            .field("batch_size", &self.batch_size)
+            .field("parse_threads", &self.parse_threads)
+            .field("mmap", &self.mmap)
+            .field("metrics", &self.metrics.is_some())
+            .field("input_format", &self.input_format)
+            .field("dialect", &self.dialect)
+            .field("strict", &self.strict)
+            .field("output_format", &self.output_format)
+            .field("rejections", &self.rejections.is_some())
+            .field("idempotency", &self.idempotency)
+            .field("snapshots", &self.snapshots)
+            .field("report_mode", &self.report_mode)
+            .field("events", &self.events.is_some())
+            .field("store", &self.store.is_some())
+            .field("dispute_window", &self.dispute_window)
+            .field("history", &self.history.is_some())
+            .field("traced_clients", &self.traced_clients)
+            .finish()
+    }
+}
+
+impl Config {
+    /// Creates new CSV [`Config`] with the given channel capacity.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            batch_size: DEFAULT_BATCH_SIZE,
+            parse_threads: 1,
+            mmap: false,
+            metrics: None,
+            input_format: Format::default(),
+            dialect: CsvDialect::default(),
+            strict: false,
+            output_format: Format::default(),
+            rejections: None,
+            idempotency: None,
+            snapshots: None,
+            report_mode: ReportMode::default(),
+            events: None,
+            store: None,
+            dispute_window: DisputeWindow::default(),
+            history: None,
+            traced_clients: Vec::new(),
+        }
+    }
+}
+
 /// Run everything.
 #[tracing::instrument(name = "Run all", skip(reader, writer, capacity))]
 pub fn run(
     reader: impl io::Read + Send + 'static,
-    writer: impl io::Write + Send + 'static,
+    writer: impl Finish + Send + 'static,
     capacity: usize,
 ) {
-    let (outgoing, incoming) = channel::bounded(capacity);
-    let mut reader = Reader::from_reader(reader, outgoing);
-    let mut writer = Writer::from_writer(writer, incoming);
+    if let Err(err) = run_with(reader, writer, Config::new(capacity)) {
+        tracing::error!(err.cause_chain=?err);
+    }
+}
+
+/// Run everything with the given configuration.
+#[tracing::instrument(name = "Run all with config", skip(reader, writer))]
+pub fn run_with(
+    reader: impl io::Read + Send + 'static,
+    writer: impl Finish + Send + 'static,
+    config: Config,
+) -> Result<()> {
+    let (outgoing, incoming) =
+        channel::bounded(channel_capacity(config.capacity, config.batch_size));
+    let decoder = config.input_format.decoder(reader, &config.dialect)?;
+    let reader = new_pipeline_reader(decoder, outgoing, &config);
+    run_pipeline(reader, incoming, writer, config)
+}
+
+/// Run everything on the input file with the given configuration.
+///
+/// An uncompressed CSV file is parsed with [`Config::parse_threads`] threads.
+/// Otherwise an uncompressed file is memory-mapped if [`Config::mmap`] is set.
+/// Other inputs, including the standard input, are streamed as with [`run_with`].
+#[tracing::instrument(name = "Run all on file", skip(path, writer))]
+pub fn run_file(
+    path: impl AsRef<Path>,
+    writer: impl Finish + Send + 'static,
+    config: Config,
+) -> Result<()> {
+    let path = path.as_ref();
+    let parallel = config.parse_threads > 1
+        && config.input_format == Format::Csv
+        && path != Path::new(STDIO_PATH)
+        && ParallelReader::supports(&config.dialect)
+        && compression::detect_file(path)? == Compression::None;
+    let (outgoing, incoming) =
+        channel::bounded(channel_capacity(config.capacity, config.batch_size));
+    if !parallel {
+        let mapped = if config.mmap {
+            new_mapped_reader(path)?
+        } else {
+            None
+        };
+        let decoder = match mapped {
+            Some(mapped) => config
+                .input_format
+                .buffered_decoder(mapped, &config.dialect)?,
+            None => config
+                .input_format
+                .decoder(new_reader(path)?, &config.dialect)?,
+        };
+        let reader = new_pipeline_reader(decoder, outgoing, &config);
+        return run_pipeline(reader, incoming, writer, config);
+    }
+
+    let mut reader =
+        ParallelReader::new(path, config.dialect.clone(), config.parse_threads, outgoing)
+            .with_strict_mode(config.strict)
+            .with_batch_size(config.batch_size);
+    if let Some(metrics) = &config.metrics {
+        reader = reader.with_metrics(metrics.clone());
+    }
+    run_pipeline(reader, incoming, writer, config)
+}
+
+/// Runs the reader and the transaction writer, then writes the account report.
+fn run_pipeline(
+    mut reader: impl Sender + Send + 'static,
+    incoming: channel::Receiver<Batch>,
+    writer: impl Finish + Send + 'static,
+    mut config: Config,
+) -> Result<()> {
+    let mut writer = new_pipeline_writer(writer, incoming, &mut config);
 
     let r_handle = thread::spawn(move || reader.send());
+    let w_handle = thread::spawn(move || {
+        writer.process_transaction();
+        writer
+    });
+
+    let sent = r_handle.join();
+    let writer = w_handle.join();
+    match sent {
+        Ok(Err(err)) => return Err(err),
+        Err(err) => tracing::error!(err.cause_chain=?err),
+        Ok(Ok(())) => {}
+    }
+    let reported = match writer {
+        Ok(mut writer) => writer.report(),
+        Err(err) => {
+            tracing::error!(err.cause_chain=?err);
+            Ok(())
+        }
+    };
+    if let Some(metrics) = &config.metrics {
+        let metrics = metrics.snapshot();
+        tracing::info!(
+            reader.transactions_per_sec = metrics.reader.transactions_per_sec,
+            reader.blocked_nanos = metrics.reader.blocked_nanos,
+            writer.transactions_per_sec = metrics.writer.transactions_per_sec,
+            writer.blocked_nanos = metrics.writer.blocked_nanos,
+            channel.max_occupancy = metrics.channel.max_occupancy,
+            "pipeline metrics"
+        );
+    }
+    reported
+}
+
+/// Listeners of the long-running server mode.
+#[derive(Debug, Default)]
+pub struct Listeners {
+    /// Listener accepting newline delimited transactions.
+    pub tcp: Option<TcpListener>,
+    /// Listener accepting HTTP API requests.
+    pub http: Option<TcpListener>,
+    /// Listener answering Prometheus scrapes.
+    pub metrics: Option<TcpListener>,
+}
+
+/// Serves transactions received on the listeners until the shutdown is
+/// triggered, then writes the account report.
+#[tracing::instrument(name = "Serve all", skip(listeners, writer, shutdown))]
+pub fn serve(
+    listeners: Listeners,
+    writer: impl Finish + Send + 'static,
+    mut config: Config,
+    shutdown: Shutdown,
+) -> Result<()> {
+    let (outgoing, incoming) =
+        channel::bounded(channel_capacity(config.capacity, config.batch_size));
+    if listeners.metrics.is_some() && config.metrics.is_none() {
+        config.metrics = Some(Metrics::new());
+    }
+    let mut writer = new_pipeline_writer(writer, incoming.clone(), &mut config);
+
+    let mut services = vec![];
+    if let (Some(listener), Some(metrics)) = (listeners.metrics, &config.metrics) {
+        let server = MetricsServer::new(listener, metrics.clone(), incoming, shutdown.clone())?;
+        services.push(thread::spawn(move || server.serve()));
+    }
+    if let Some(listener) = listeners.http {
+        let server = HttpServer::new(
+            listener,
+            writer.registry(),
+            outgoing.clone(),
+            shutdown.clone(),
+        )?;
+        services.push(thread::spawn(move || server.serve()));
+    }
+    if let Some(listener) = listeners.tcp {
+        let server = Server::new(
+            listener,
+            config.input_format,
+            config.dialect,
+            outgoing.clone(),
+            shutdown,
+        );
+        services.push(thread::spawn(move || server.serve()));
+    }
+    drop(outgoing);
+
     let w_handle = thread::spawn(move || writer.write());
+    let mut served = Ok(());
+    for service in services {
+        match service.join() {
+            Ok(Err(err)) => served = Err(err),
+            Err(err) => tracing::error!(err.cause_chain=?err),
+            Ok(Ok(())) => {}
+        }
+    }
+    match w_handle.join() {
+        Ok(Err(err)) => served = served.and(Err(err)),
+        Err(err) => tracing::error!(err.cause_chain=?err),
+        Ok(Ok(())) => {}
+    }
+    served
+}
 
-    if let Err(err) = r_handle.join() {
-        tracing::error!(err.cause_chain=?err);
+/// Creates the transaction reader configured with the input options.
+fn new_pipeline_reader(
+    decoder: Box<dyn Decoder + Send>,
+    outgoing: channel::Sender<Batch>,
+    config: &Config,
+) -> Reader<Box<dyn Decoder + Send>> {
+    let mut reader = Reader::new(decoder, outgoing)
+        .with_strict_mode(config.strict)
+        .with_batch_size(config.batch_size);
+    if let Some(metrics) = &config.metrics {
+        reader = reader.with_metrics(metrics.clone());
     }
-    if let Err(err) = w_handle.join() {
-        tracing::error!(err.cause_chain=?err);
+    reader
+}
+
+/// Creates the transaction writer configured with the report options.
+fn new_pipeline_writer(
+    writer: impl Finish + Send + 'static,
+    incoming: channel::Receiver<Batch>,
+    config: &mut Config,
+) -> Writer<Box<dyn Encoder<AccountSnapshot> + Send>> {
+    let mut writer = Writer::new(config.output_format.encoder(writer), incoming)
+        .with_report_mode(config.report_mode);
+    if let Some(store) = config.store.take() {
+        writer = writer.with_store(store);
+    }
+    if let Some(history) = config.history.take() {
+        writer = writer.with_history(history);
     }
+    writer = writer.with_dispute_window(config.dispute_window);
+    if let Some(rejections) = config.rejections.take() {
+        writer = writer.with_rejections(config.output_format.encoder(rejections));
+    }
+    if let Some(store) = config.idempotency.take() {
+        writer = writer.with_idempotency(store);
+    }
+    if let Some(events) = config.events.take() {
+        writer = writer.with_events(events);
+    }
+    if let Some(snapshots) = config.snapshots.take() {
+        writer = writer.with_snapshots(snapshots);
+    }
+    if let Some(metrics) = &config.metrics {
+        writer = writer.with_metrics(metrics.clone());
+    }
+    writer.with_traced_clients(config.traced_clients.drain(..))
 }
 
 /// Creates an io::Reader from file path.
-fn new_reader(path: impl AsRef<Path>) -> Result<impl io::Read> {
-    File::open(path).map_err(Error::IoError)
+///
+/// The path `-` reads from the standard input. Compressed inputs are detected
+/// from the file extension or the magic bytes and decompressed on the fly.
+pub fn new_reader(path: impl AsRef<Path>) -> Result<Box<dyn io::Read + Send>> {
+    let path = path.as_ref();
+    if path == Path::new(STDIO_PATH) {
+        return compression::detect_decoder(io::stdin(), None);
+    }
+    let file = File::open(path).map_err(Error::IoError)?;
+    compression::detect_decoder(file, Some(path))
 }
 
-/// Gets input file.
+/// Creates a buffered io::Reader over the memory-mapped file, exposing the whole
+/// file as a single buffer.
+///
+/// Returns `None` for the standard input and for compressed files, which must
+/// be streamed with [`new_reader`] instead. The file must not be modified
+/// while it is mapped.
+pub fn new_mapped_reader(path: impl AsRef<Path>) -> Result<Option<Box<dyn io::BufRead + Send>>> {
+    let path = path.as_ref();
+    if path == Path::new(STDIO_PATH) || compression::detect_file(path)? != Compression::None {
+        return Ok(None);
+    }
+    let file = File::open(path).map_err(Error::IoError)?;
+    // SAFETY: the mapping is read-only and the input file is not expected to
+    // change while the engine processes it.
+    let mmap = unsafe { Mmap::map(&file) }.map_err(Error::IoError)?;
+    #[cfg(unix)]
+    mmap.advise(memmap2::Advice::Sequential)
+        .map_err(Error::IoError)?;
+    Ok(Some(Box::new(io::Cursor::new(mmap))))
+}
+
+/// Creates an io::Writer from file path.
+///
+/// The path `-` writes to the standard output. When `compression` is not
+/// specified, it is detected from the file extension.
+pub fn new_writer(
+    path: impl AsRef<Path>,
+    compression: Option<Compression>,
+) -> Result<Box<dyn Finish + Send>> {
+    let path = path.as_ref();
+    let compression = compression
+        .or_else(|| Compression::from_path(path))
+        .unwrap_or(Compression::None);
+    if path == Path::new(STDIO_PATH) {
+        return Ok(Box::new(compression.encoder(io::stdout())?));
+    }
+    let file = File::create(path).map_err(Error::IoError)?;
+    Ok(Box::new(compression.encoder(io::BufWriter::new(file))?))
+}
 
-pub fn get_input() -> Result<impl io::Read> {
+/// Gets input file.
+pub fn get_input() -> Result<Box<dyn io::Read + Send>> {
     let path = std::env::args().nth(1).ok_or(Error::InvalidArgumentError)?;
     new_reader(path)
 }
@@ -22,6 +22,9 @@ pub struct TransactionData {
     pub(crate) id: TransactionId,
 
     pub(crate) amount: Option<Decimal>,
+
+    /// Client supplied key used to deduplicate resubmitted transactions.
+    pub(crate) idempotency_key: Option<String>,
 }
 
 /// [`RawTransaction`] represents non validated transaction.
@@ -34,22 +37,25 @@ struct RawTransactionData {
     #[serde(rename(deserialize = "tx"))]
     id: u32,
     amount: Option<Decimal>,
+    #[serde(default)]
+    idempotency_key: Option<String>,
 }
 
-impl TryFrom<RawTransactionData> for TransactionData {
-    type Error = Error;
-    fn try_from(raw: RawTransactionData) -> Result<Self, Self::Error> {
-        let RawTransactionData {
-            client,
-            tx_type,
-            id,
-            amount,
-        } = raw;
+impl TransactionData {
+    /// Creates new validated [`TransactionData`]. An empty idempotency key is ignored.
+    pub(crate) fn new(
+        client: u16,
+        tx_type: TransactionType,
+        id: u32,
+        amount: Option<Decimal>,
+        idempotency_key: Option<String>,
+    ) -> Result<Self, Error> {
         let transaction = TransactionData {
             client: Client::from(client),
             tx_type,
             id: TransactionId::from(id),
             amount,
+            idempotency_key: idempotency_key.filter(|key| !key.is_empty()),
         };
         match transaction.tx_type {
             TransactionType::Deposit | TransactionType::Withdrawal if amount.is_none() => {
@@ -65,6 +71,20 @@ impl TryFrom<RawTransactionData> for TransactionData {
     }
 }
 
+impl TryFrom<RawTransactionData> for TransactionData {
+    type Error = Error;
+    fn try_from(raw: RawTransactionData) -> Result<Self, Self::Error> {
+        let RawTransactionData {
+            client,
+            tx_type,
+            id,
+            amount,
+            idempotency_key,
+        } = raw;
+        Self::new(client, tx_type, id, amount, idempotency_key)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -1,10 +1,10 @@
 //! Transaction ID type.
 //!
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// The [`TransactionId`] type is a unique ID associated to each transaction.
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TransactionId(u32);
 
 impl TransactionId {
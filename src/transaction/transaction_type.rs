@@ -8,7 +8,7 @@ use std::fmt;
 use serde::{Deserialize, Serialize};
 
 /// [`TransactionType`] is a type that represents the different possible operations.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -27,6 +27,6 @@ impl fmt::Display for TransactionType {
             Self::Resolve => "resolve",
             Self::ChargeBack => "chargeback",
         };
-        write!(f, "{}", value)
+        write!(f, "{value}")
     }
 }
@@ -1,17 +1,214 @@
 //! Transport traits.
 //!
 //! This module defines the transport traits which specifies the behavior for
-//! sending and receiving transaction data.
+//! sending and receiving transaction data, and the [`Envelope`] type which
+//! carries a transaction through the pipeline.
+//!
+//! Transactions move through the pipeline channel in [`Batch`]es, so that the
+//! channel synchronization cost is paid once per batch rather than once per
+//! transaction. The channel is bounded in batches, which preserves backpressure.
+
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
 
+use crossbeam::channel;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::metrics::Metrics;
 use crate::prelude::TransactionData;
 use crate::Result;
 
+/// Default maximum number of transactions in a batch.
+pub const DEFAULT_BATCH_SIZE: usize = 256;
+
+/// A batch of transactions moved through the pipeline channel at once.
+pub type Batch = Vec<Envelope>;
+
 /// The [`Sender`] trait specifies the behavior for sending transaction data.
 pub trait Sender {
+    /// Sends all the transactions, in batches of at most
+    /// [`batch_size`](Self::batch_size) transactions.
     fn send(&mut self) -> Result<()>;
+
+    /// Returns the maximum number of transactions in a batch.
+    fn batch_size(&self) -> usize;
 }
 
 /// The [`Receiver`] trait specifies the behavior for receiving transaction data.
 pub trait Receiver {
-    fn recv(&mut self) -> Result<TransactionData>;
+    /// Receives the next batch of transactions.
+    fn recv(&mut self) -> Result<Batch>;
+}
+
+/// Returns the number of batches a channel must hold for `capacity` transactions
+/// to be in flight.
+pub fn channel_capacity(capacity: usize, batch_size: usize) -> usize {
+    (capacity / batch_size.max(1)).max(1)
+}
+
+/// [`BatchSender`] groups the envelopes into batches sent over a channel.
+#[derive(Debug)]
+pub struct BatchSender {
+    sender: channel::Sender<Batch>,
+    batch: Batch,
+    batch_size: usize,
+    metrics: Option<Metrics>,
+}
+
+impl BatchSender {
+    /// Creates new [`BatchSender`] sending batches of `batch_size` envelopes.
+    pub fn new(sender: channel::Sender<Batch>, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        Self {
+            sender,
+            batch: Vec::with_capacity(batch_size),
+            batch_size,
+            metrics: None,
+        }
+    }
+
+    /// Records the batches sent and the time blocked on a full channel.
+    pub fn with_metrics(mut self, metrics: Option<Metrics>) -> Self {
+        if let Some(metrics) = &metrics {
+            metrics.start_reader();
+        }
+        self.metrics = metrics;
+        self
+    }
+
+    /// Adds the envelope to the batch, sending the batch once full. Blocks while
+    /// the channel is full.
+    pub fn push(&mut self, envelope: Envelope) -> Result<()> {
+        self.batch.push(envelope);
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Sends the pending envelopes, if any.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        let Some(metrics) = &self.metrics else {
+            return self
+                .sender
+                .send(batch)
+                .map_err(|e| Error::SendError(e.to_string()));
+        };
+
+        let transactions = batch.len();
+        let mut blocked = Duration::ZERO;
+        match self.sender.try_send(batch) {
+            Ok(()) => {}
+            Err(channel::TrySendError::Full(batch)) => {
+                let start = Instant::now();
+                self.sender
+                    .send(batch)
+                    .map_err(|e| Error::SendError(e.to_string()))?;
+                blocked = start.elapsed();
+            }
+            Err(err) => return Err(Error::SendError(err.to_string())),
+        }
+        metrics.record_send(
+            transactions,
+            blocked,
+            self.sender.len(),
+            self.sender.capacity(),
+        );
+        Ok(())
+    }
+}
+
+/// [`Outcome`] is the result of applying a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", content = "reason", rename_all = "lowercase")]
+pub enum Outcome {
+    Applied,
+    Rejected(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Applied => write!(f, "applied"),
+            Self::Rejected(reason) => write!(f, "rejected: {reason}"),
+        }
+    }
+}
+
+/// [`Envelope`] wraps a transaction with an optional channel on which the
+/// transaction [`Outcome`] is acknowledged.
+#[derive(Debug)]
+pub struct Envelope {
+    pub transaction: TransactionData,
+    pub(crate) reply: Option<channel::Sender<Outcome>>,
+}
+
+impl Envelope {
+    /// Creates new [`Envelope`] acknowledging the outcome on the `reply` channel.
+    pub fn with_reply(transaction: TransactionData, reply: channel::Sender<Outcome>) -> Self {
+        Self {
+            transaction,
+            reply: Some(reply),
+        }
+    }
+
+    /// Acknowledges the transaction outcome if a reply channel is attached.
+    pub(crate) fn acknowledge(&self, outcome: Outcome) {
+        if let Some(reply) = &self.reply {
+            // The requester may have gone away, which is not an error for the pipeline.
+            let _ = reply.send(outcome);
+        }
+    }
+}
+
+impl From<TransactionData> for Envelope {
+    fn from(transaction: TransactionData) -> Self {
+        Self {
+            transaction,
+            reply: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{Client, TransactionId, TransactionType};
+
+    fn envelope(id: u32) -> Envelope {
+        Envelope::from(TransactionData {
+            client: Client::from(1),
+            tx_type: TransactionType::Deposit,
+            id: TransactionId::from(id),
+            amount: Some(1.into()),
+            idempotency_key: None,
+        })
+    }
+
+    #[test]
+    fn send_envelopes_in_batches() {
+        let (sender, receiver) = channel::bounded(channel_capacity(10, 4));
+        let mut batches = BatchSender::new(sender, 4);
+        for id in 0..6 {
+            batches.push(envelope(id)).unwrap();
+        }
+        assert_eq!(receiver.try_recv().unwrap().len(), 4);
+        assert!(receiver.try_recv().is_err(), "partial batch is pending");
+
+        batches.flush().unwrap();
+        let batch = receiver.try_recv().unwrap();
+        let ids = batch
+            .iter()
+            .map(|envelope| *envelope.transaction.id.inner_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [4, 5]);
+        batches.flush().unwrap();
+        assert!(receiver.try_recv().is_err(), "empty batch is not sent");
+    }
 }
@@ -0,0 +1,63 @@
+#![cfg(unix)]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Returns a free local address.
+fn free_addr() -> std::net::SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Connects to the server, retrying until it listens.
+fn connect(addr: std::net::SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            return stream;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("server did not listen on {addr}");
+}
+
+#[test]
+fn terminate_server_and_write_the_report() {
+    let addr = free_addr();
+    let dir = tempfile::tempdir().unwrap();
+    let metrics = dir.path().join("metrics.json");
+    let engine = Command::new(env!("CARGO_BIN_EXE_payeng"))
+        .arg("--listen")
+        .arg(addr.to_string())
+        .arg("--metrics")
+        .arg(&metrics)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stream = connect(addr);
+    let mut acks = BufReader::new(stream.try_clone().unwrap()).lines();
+    for line in ["deposit, 1, 1, 2.0", "withdrawal, 1, 2, 0.5"] {
+        writeln!(stream, "{line}").unwrap();
+        assert_eq!(acks.next().unwrap().unwrap(), "applied");
+    }
+    drop(stream);
+
+    let killed = Command::new("kill")
+        .arg("-TERM")
+        .arg(engine.id().to_string())
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    let output = engine.wait_with_output().unwrap();
+
+    assert!(output.status.success(), "{:?}", output.status);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+    );
+    assert!(metrics.exists(), "the metrics are written on shutdown");
+}
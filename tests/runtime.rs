@@ -1,7 +1,19 @@
 use parking_lot::Mutex;
-use payeng::prelude::{runtime, Client};
+use payeng::compression::{Compression, Finish};
+use payeng::error::Error;
+use payeng::format::Format;
+use payeng::idempotency::IdempotencyStore;
+use payeng::metrics::Metrics;
+use payeng::prelude::{
+    runtime, AccountEventKind, ChannelSink, Client, CompactHistory, DiskHistory, DisputeWindow,
+    LogStore, Rejection, ReportMode, SharedHistory, TransactionId,
+};
+use payeng::server::Shutdown;
+use payeng::snapshot::{SnapshotPolicy, Snapshots};
+use payeng::transport::Outcome;
 use rust_decimal::Decimal;
 
+use std::io::{BufRead, Read, Write};
 use std::sync::Arc;
 
 struct TestWriter {
@@ -18,6 +30,8 @@ impl std::io::Write for TestWriter {
     }
 }
 
+impl Finish for TestWriter {}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct Record {
     pub client: Client,
@@ -27,6 +41,16 @@ struct Record {
     pub locked: bool,
 }
 
+fn parse_records(content: &[u8]) -> Vec<Record> {
+    let mut records = vec![];
+    for result in csv::Reader::from_reader(content).deserialize() {
+        let record: Record = result.expect("failed to get record");
+        records.push(record);
+    }
+    records.sort_by_key(|v| v.client.clone());
+    records
+}
+
 #[test]
 fn run_output_expected_value() {
     let dir = std::env::current_dir().expect("failed to get current directory");
@@ -40,12 +64,862 @@ fn run_output_expected_value() {
     let content = content.lock().clone();
     let content = String::from_utf8(content).expect("failed to convert to string");
 
-    let mut records = vec![];
-    for result in csv::Reader::from_reader(content.as_bytes()).deserialize() {
-        let record: Record = result.expect("failed to get record");
-        records.push(record);
+    let records = parse_records(content.as_bytes());
+    insta::assert_csv_snapshot!(records);
+}
+
+#[test]
+fn run_with_compressed_input_and_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = std::fs::read("tests/test.csv").unwrap();
+
+    for (compression, extension) in [(Compression::Gzip, "gz"), (Compression::Zstd, "zst")] {
+        let input_path = dir.path().join(format!("test.csv.{extension}"));
+        let mut encoder = compression
+            .encoder(std::fs::File::create(&input_path).unwrap())
+            .unwrap();
+        encoder.write_all(&input).unwrap();
+        encoder.finish().unwrap();
+
+        let output_path = dir.path().join(format!("report.csv.{extension}"));
+        let reader = runtime::new_reader(&input_path).unwrap();
+        let writer = runtime::new_writer(&output_path, None).unwrap();
+        runtime::run(reader, writer, 20);
+
+        let mut content = vec![];
+        runtime::new_reader(&output_path)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        insta::assert_csv_snapshot!("run_output_expected_value", parse_records(&content));
+    }
+}
+
+/// Writer failing to complete its output, like a full disk.
+struct UnfinishedWriter;
+
+impl std::io::Write for UnfinishedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Finish for UnfinishedWriter {
+    fn finish(&mut self) -> std::io::Result<()> {
+        Err(std::io::Error::other("no space left"))
     }
+}
+
+#[test]
+fn run_with_unfinished_report() {
+    let reader = std::fs::File::open("tests/test.csv").unwrap();
+    let result = runtime::run_with(reader, UnfinishedWriter, runtime::Config::new(20));
+    assert!(matches!(result, Err(Error::IoError(_))));
+}
+
+#[test]
+fn run_with_corrupt_compressed_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("test.csv.gz");
+    std::fs::write(
+        &path,
+        b"\x1f\x8b\x08\x00garbage which is not a deflate stream",
+    )
+    .unwrap();
+
+    let reader = runtime::new_reader(&path).unwrap();
+    let result = runtime::run_with(reader, std::io::sink(), runtime::Config::new(20));
+    assert!(matches!(result, Err(Error::IoError(_))), "found {result:?}");
+}
+
+#[test]
+fn run_with_json_lines_input_and_output() {
+    let reader = std::fs::File::open("tests/test.jsonl").unwrap();
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let config = runtime::Config {
+        input_format: Format::JsonLines,
+        output_format: Format::JsonLines,
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(reader, writer, config).unwrap();
 
+    let content = content.lock().clone();
+    let mut records = content
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice::<Record>(line).expect("failed to get record"))
+        .collect::<Vec<_>>();
     records.sort_by_key(|v| v.client.clone());
-    insta::assert_csv_snapshot!(records);
+    insta::assert_csv_snapshot!("run_output_expected_value", records);
+}
+
+#[test]
+fn run_with_rejection_report() {
+    let reader = std::fs::File::open("tests/test.csv").unwrap();
+    let report = Arc::new(Mutex::new(vec![]));
+    let rejections = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: report.clone(),
+    };
+    let config = runtime::Config {
+        rejections: Some(Box::new(TestWriter {
+            content: rejections.clone(),
+        })),
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(reader, writer, config).unwrap();
+
+    let content = rejections.lock().clone();
+    let rejections = csv::Reader::from_reader(content.as_slice())
+        .deserialize::<Rejection>()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("failed to get rejections");
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].client, Client::from(2));
+    assert_eq!(rejections[0].tx, TransactionId::from(5));
+    assert_eq!(rejections[0].reason, "insufficient available funds");
+}
+
+#[test]
+fn strict_run_aborts_on_first_malformed_record() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 4, 9.0\nwithdrawal, 1, 2\ndeposit, 1, 3, 2.0\n";
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let rejections = Arc::new(Mutex::new(vec![]));
+    let (sender, events) = crossbeam::channel::unbounded();
+    let config = runtime::Config {
+        strict: true,
+        rejections: Some(Box::new(TestWriter {
+            content: rejections.clone(),
+        })),
+        events: Some(Box::new(ChannelSink::new(sender))),
+        ..runtime::Config::new(20)
+    };
+
+    match runtime::run_with(std::io::Cursor::new(input), writer, config) {
+        Err(Error::MalformedRecord { line, record, .. }) => {
+            assert_eq!(line, 4);
+            assert_eq!(record, "withdrawal,1,2");
+        }
+        result => panic!("expected a malformed record error, found {result:?}"),
+    }
+    assert!(content.lock().is_empty(), "no partial report is written");
+    assert!(rejections.lock().is_empty(), "no rejection is written");
+    assert_eq!(events.try_iter().count(), 0, "no transaction is applied");
+}
+
+#[test]
+fn serve_acknowledges_transactions_from_concurrent_connections() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let shutdown = Shutdown::new();
+    let server = {
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || {
+            let listeners = runtime::Listeners {
+                tcp: Some(listener),
+                ..Default::default()
+            };
+            runtime::serve(listeners, writer, runtime::Config::new(20), shutdown)
+        })
+    };
+
+    let clients = [
+        [
+            "deposit, 1, 1, 1.0",
+            "deposit, 1, 3, 2.0",
+            "withdrawal, 1, 4, 1.5",
+        ],
+        ["deposit, 2, 2, 2.0", "withdrawal, 2, 5, 3.0", "deposit, 2"],
+    ]
+    .map(|lines| {
+        std::thread::spawn(move || {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let mut acks = std::io::BufReader::new(stream.try_clone().unwrap()).lines();
+            let mut stream = stream;
+            lines
+                .iter()
+                .map(|line| {
+                    writeln!(stream, "{line}").unwrap();
+                    acks.next().unwrap().unwrap()
+                })
+                .collect::<Vec<_>>()
+        })
+    })
+    .map(|client| client.join().unwrap());
+
+    assert_eq!(clients[0], ["applied", "applied", "applied"]);
+    assert_eq!(
+        clients[1][..2],
+        ["applied", "rejected: insufficient available funds"]
+    );
+    assert!(clients[1][2].starts_with("rejected: malformed record at line 3"));
+
+    shutdown.trigger();
+    server.join().unwrap().unwrap();
+    insta::assert_csv_snapshot!("run_output_expected_value", parse_records(&content.lock()));
+}
+
+/// Sends an HTTP request and returns the response status and JSON body.
+fn http_request(
+    addr: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, serde_json::Value) {
+    let (status, body) = http_request_text(addr, method, path, body);
+    (status, serde_json::from_str(&body).unwrap())
+}
+
+/// Sends an HTTP request and returns the response status and body.
+fn http_request_text(
+    addr: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, body.to_string())
+}
+
+#[test]
+fn serve_http_api() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let shutdown = Shutdown::new();
+    let server = {
+        let shutdown = shutdown.clone();
+        let listeners = runtime::Listeners {
+            http: Some(listener),
+            ..Default::default()
+        };
+        let config = runtime::Config {
+            history: Some(Arc::new(Mutex::new(CompactHistory::new()))),
+            ..runtime::Config::new(20)
+        };
+        std::thread::spawn(move || runtime::serve(listeners, std::io::sink(), config, shutdown))
+    };
+
+    for line in std::fs::read_to_string("tests/test.jsonl").unwrap().lines() {
+        http_request(addr, "POST", "/transactions", line);
+    }
+    let (status, body) = http_request(
+        addr,
+        "POST",
+        "/transactions",
+        r#"{"type": "dispute", "client": 1, "tx": 3}"#,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(body["status"], "applied");
+
+    let (status, body) = http_request(
+        addr,
+        "POST",
+        "/transactions",
+        r#"{"type": "withdrawal", "client": 2, "tx": 6, "amount": 5}"#,
+    );
+    assert_eq!(status, 422);
+    assert_eq!(body["reason"], "insufficient available funds");
+
+    let (status, body) = http_request(addr, "POST", "/transactions", r#"{"type": "deposit"}"#);
+    assert_eq!(status, 400);
+    assert_eq!(body["status"], "rejected");
+
+    let (status, body) = http_request(addr, "GET", "/accounts/1", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["available"], "1.5");
+    assert_eq!(body["held"], "2");
+
+    let (_, body) = http_request(addr, "GET", "/accounts", "");
+    assert_eq!(body.as_array().map(Vec::len), Some(2));
+    let (status, body) = http_request(addr, "GET", "/accounts?client=1", "");
+    assert_eq!(status, 200);
+    assert_eq!(body.as_array().map(Vec::len), Some(2));
+
+    let (status, body) = http_request(addr, "GET", "/transactions/3", "");
+    assert_eq!(status, 200);
+    assert_eq!(body["client"], 1);
+    assert_eq!(body["state"], "disputed");
+
+    assert_eq!(http_request(addr, "GET", "/accounts/42", "").0, 404);
+    assert_eq!(http_request(addr, "GET", "/transactions/42", "").0, 404);
+    assert_eq!(http_request(addr, "DELETE", "/accounts", "").0, 405);
+
+    shutdown.trigger();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn serve_prometheus_metrics() {
+    let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let (http_addr, metrics_addr) = (http.local_addr().unwrap(), metrics.local_addr().unwrap());
+    let shutdown = Shutdown::new();
+    let server = {
+        let shutdown = shutdown.clone();
+        let listeners = runtime::Listeners {
+            http: Some(http),
+            metrics: Some(metrics),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            runtime::serve(
+                listeners,
+                std::io::sink(),
+                runtime::Config::new(20),
+                shutdown,
+            )
+        })
+    };
+
+    for line in std::fs::read_to_string("tests/test.jsonl").unwrap().lines() {
+        http_request(http_addr, "POST", "/transactions", line);
+    }
+    for body in [
+        r#"{"type": "dispute", "client": 1, "tx": 3}"#,
+        r#"{"type": "withdrawal", "client": 2, "tx": 6, "amount": 5}"#,
+        r#"{"type": "resolve", "client": 2, "tx": 42}"#,
+    ] {
+        http_request(http_addr, "POST", "/transactions", body);
+    }
+
+    let (status, body) = http_request_text(metrics_addr, "GET", "/metrics", "");
+    assert_eq!(status, 200);
+    for line in [
+        "payeng_transactions_applied_total{type=\"deposit\"} 3",
+        "payeng_transactions_applied_total{type=\"dispute\"} 1",
+        "payeng_transactions_rejected_total{type=\"withdrawal\",reason=\"insufficient_funds\"} 2",
+        "payeng_transactions_rejected_total{type=\"resolve\",reason=\"dispute_state\"} 1",
+        "payeng_accounts 2",
+        "payeng_accounts_locked 0",
+        "payeng_held_funds 2",
+        "payeng_channel_depth 0",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "missing `{line}` in\n{body}"
+        );
+    }
+    assert_eq!(
+        http_request_text(metrics_addr, "GET", "/metrics?format=text", "").0,
+        200
+    );
+    assert_eq!(http_request_text(metrics_addr, "GET", "/", "").0, 404);
+    assert_eq!(
+        http_request(http_addr, "GET", "/transactions/3", "").0,
+        501,
+        "transaction lookup should need a history index"
+    );
+
+    shutdown.trigger();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn resubmitted_transactions_are_applied_once() {
+    let input = "type, client, tx, amount, idempotency_key
+deposit, 1, 1, 1.0, k1
+deposit, 1, 1, 1.0, k1
+withdrawal, 1, 2, 5.0, k2
+deposit, 1, 3, 5.0,
+withdrawal, 1, 2, 5.0, k2
+deposit, 1, 4, 2.0, k1
+";
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("keys.jsonl");
+
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let config = runtime::Config {
+        idempotency: Some(IdempotencyStore::open(&path, 10).unwrap()),
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+
+    let records = parse_records(&content.lock());
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].available, Decimal::from(6));
+
+    let store = IdempotencyStore::open(&path, 10).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("k1"), Some(&Outcome::Applied));
+    assert!(matches!(store.get("k2"), Some(Outcome::Rejected(_))));
+}
+
+#[test]
+fn serve_writes_periodic_snapshots() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let policy = SnapshotPolicy {
+        every_transactions: Some(2),
+        ..SnapshotPolicy::default()
+    };
+    let snapshots = Snapshots::new(dir.path(), Format::Csv, Compression::None, policy).unwrap();
+    let trigger = snapshots.trigger();
+    let config = runtime::Config {
+        snapshots: Some(snapshots),
+        ..runtime::Config::new(20)
+    };
+    let shutdown = Shutdown::new();
+    let server = {
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || {
+            let listeners = runtime::Listeners {
+                tcp: Some(listener),
+                ..Default::default()
+            };
+            runtime::serve(listeners, std::io::sink(), config, shutdown)
+        })
+    };
+
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    let mut acks = std::io::BufReader::new(stream.try_clone().unwrap()).lines();
+    let mut stream = stream;
+    for line in [
+        "deposit, 1, 1, 1.0",
+        "deposit, 1, 2, 2.0",
+        "withdrawal, 1, 3, 0.5",
+    ] {
+        writeln!(stream, "{line}").unwrap();
+        assert_eq!(acks.next().unwrap().unwrap(), "applied");
+    }
+
+    let read_snapshot = |name: &str| {
+        let path = dir.path().join(name);
+        for _ in 0..100 {
+            if let Ok(content) = std::fs::read(&path) {
+                if !content.is_empty() {
+                    return parse_records(&content);
+                }
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("snapshot {name} not written");
+    };
+    assert_eq!(
+        read_snapshot("accounts-000001.csv")[0].available,
+        Decimal::from(3)
+    );
+
+    trigger.request();
+    let records = read_snapshot("accounts-000002.csv");
+    assert_eq!(records[0].available, Decimal::new(25, 1));
+
+    shutdown.trigger();
+    drop(stream);
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn delta_reports_only_contain_changed_accounts() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 3, 3, 1.0
+deposit, 1, 4, 1.5
+";
+    let dir = tempfile::tempdir().unwrap();
+    let policy = SnapshotPolicy {
+        every_transactions: Some(3),
+        ..SnapshotPolicy::default()
+    };
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let config = runtime::Config {
+        snapshots: Some(
+            Snapshots::new(dir.path(), Format::Csv, Compression::None, policy).unwrap(),
+        ),
+        report_mode: ReportMode::Delta,
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+
+    let clients = |records: &[Record]| {
+        let mut clients = records
+            .iter()
+            .map(|record| record.client.clone())
+            .collect::<Vec<_>>();
+        clients.sort();
+        clients
+    };
+    let snapshot = std::fs::read(dir.path().join("accounts-000001.csv")).unwrap();
+    assert_eq!(
+        clients(&parse_records(&snapshot)),
+        [Client::from(1), Client::from(2)]
+    );
+
+    let records = parse_records(&content.lock());
+    assert_eq!(clients(&records), [Client::from(1)]);
+    assert_eq!(records[0].total, Decimal::new(25, 1));
+}
+
+#[test]
+fn run_publishes_account_events() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 3.0
+withdrawal, 1, 2, 1.0
+withdrawal, 1, 3, 9.0
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+    let (sender, receiver) = crossbeam::channel::unbounded();
+    let config = runtime::Config {
+        events: Some(Box::new(ChannelSink::new(sender))),
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(std::io::Cursor::new(input), std::io::sink(), config).unwrap();
+
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    let kinds = events.iter().map(|event| event.event).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            AccountEventKind::DepositApplied,
+            AccountEventKind::WithdrawalApplied,
+            AccountEventKind::DisputeOpened,
+            AccountEventKind::ChargedBack,
+            AccountEventKind::AccountLocked,
+        ]
+    );
+    assert_eq!(events[2].amount, Some(Decimal::from(3)));
+    assert_eq!(events[2].held, Decimal::from(3));
+    assert!(events[4].locked);
+}
+
+#[test]
+fn run_restores_accounts_from_the_log_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("accounts.log");
+    let run = |input: &'static str| {
+        let content = Arc::new(Mutex::new(vec![]));
+        let writer = TestWriter {
+            content: content.clone(),
+        };
+        let config = runtime::Config {
+            store: Some(Box::new(LogStore::open(&path, 1).unwrap())),
+            report_mode: ReportMode::Delta,
+            ..runtime::Config::new(20)
+        };
+        runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+        let records = parse_records(&content.lock());
+        records
+    };
+
+    let records = run("type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 3, 3, 3.0
+");
+    assert_eq!(records.len(), 3);
+
+    let records = run("type, client, tx, amount
+deposit, 2, 4, 0.5
+dispute, 2, 2,
+");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].client, Client::from(2));
+    assert_eq!(records[0].held, Decimal::from(2));
+    assert_eq!(records[0].available, Decimal::new(25, 1));
+}
+
+#[test]
+fn disputes_outside_the_window_are_rejected() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+dispute, 1, 2,
+deposit, 2, 3, 3.0
+deposit, 1, 4, 4.0
+dispute, 1, 1,
+dispute, 2, 3,
+resolve, 1, 2,
+dispute, 1, 9,
+";
+    for (window, expired) in [
+        (DisputeWindow::PerAccount(2), vec![1]),
+        (DisputeWindow::Global(2), vec![1, 3]),
+    ] {
+        let rejections = Arc::new(Mutex::new(vec![]));
+        let config = runtime::Config {
+            rejections: Some(Box::new(TestWriter {
+                content: rejections.clone(),
+            })),
+            dispute_window: window,
+            ..runtime::Config::new(20)
+        };
+        runtime::run_with(std::io::Cursor::new(input), std::io::sink(), config).unwrap();
+
+        let content = rejections.lock().clone();
+        let rejections = csv::Reader::from_reader(content.as_slice())
+            .deserialize::<Rejection>()
+            .collect::<Result<Vec<_>, _>>()
+            .expect("failed to get rejections");
+        let rejected = rejections
+            .iter()
+            .map(|rejection| (*rejection.tx.inner_ref(), rejection.reason.as_str()))
+            .collect::<Vec<_>>();
+        let mut expected = expired
+            .into_iter()
+            .map(|tx| (tx, "transaction is outside the dispute window"))
+            .collect::<Vec<_>>();
+        expected.push((9, "non disputed transaction"));
+        assert_eq!(rejected, expected, "unexpected rejections for {window:?}");
+    }
+}
+
+#[test]
+fn run_with_history_indexes() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+deposit, 3, 5, 5.0
+dispute, 1, 1,
+dispute, 2, 2,
+dispute, 3, 5,
+resolve, 2, 2,
+chargeback, 3, 5,
+dispute, 2, 1,
+deposit, 2, 6, 1.0
+dispute, 2, 6,
+";
+    let run = |history: Option<SharedHistory>| {
+        let content = Arc::new(Mutex::new(vec![]));
+        let writer = TestWriter {
+            content: content.clone(),
+        };
+        let config = runtime::Config {
+            history,
+            ..runtime::Config::new(20)
+        };
+        runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+        let records = parse_records(&content.lock());
+        records
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let history = DiskHistory::create(dir.path().join("history.bin"), 2).unwrap();
+    let records = run(Some(Arc::new(Mutex::new(history))));
+    assert_eq!(
+        format!("{records:?}"),
+        format!("{:?}", run(None)),
+        "disk history should not change the report"
+    );
+    assert_eq!(
+        format!("{records:?}"),
+        format!(
+            "{:?}",
+            run(Some(Arc::new(Mutex::new(CompactHistory::new()))))
+        ),
+        "compact history should not change the report"
+    );
+    assert!(records[2].locked);
+    assert_eq!(records[0].held, Decimal::from(1));
+    assert_eq!(records[1].held, Decimal::from(1));
+}
+
+#[test]
+fn history_index_keeps_transaction_of_first_client() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 1, 5.0
+dispute, 1, 1,
+";
+    let dir = tempfile::tempdir().unwrap();
+    let history = DiskHistory::create(dir.path().join("history.bin"), 2).unwrap();
+    let content = Arc::new(Mutex::new(vec![]));
+    let writer = TestWriter {
+        content: content.clone(),
+    };
+    let config = runtime::Config {
+        history: Some(Arc::new(Mutex::new(history))),
+        ..runtime::Config::new(20)
+    };
+    runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+
+    let records = parse_records(&content.lock());
+    assert_eq!(records[0].held, Decimal::from(1));
+    assert_eq!(records[1].total, Decimal::ZERO);
+}
+
+#[test]
+fn batch_size_does_not_change_the_report() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+dispute, 1, 1,
+deposit, 3, 6, 4.0
+chargeback, 1, 1,
+deposit, 1, 7, 9.0
+";
+    let run = |batch_size| {
+        let content = Arc::new(Mutex::new(vec![]));
+        let writer = TestWriter {
+            content: content.clone(),
+        };
+        let config = runtime::Config {
+            batch_size,
+            ..runtime::Config::new(4)
+        };
+        runtime::run_with(std::io::Cursor::new(input), writer, config).unwrap();
+        let records = parse_records(&content.lock());
+        format!("{records:?}")
+    };
+
+    let expected = run(1);
+    for batch_size in [2, 3, 1000] {
+        assert_eq!(run(batch_size), expected, "batch size {batch_size}");
+    }
+}
+
+#[test]
+fn parse_threads_do_not_change_the_report() {
+    let input = tempfile::NamedTempFile::new().unwrap();
+    let mut file = std::io::BufWriter::new(input.as_file());
+    writeln!(file, "type, client, tx, amount").unwrap();
+    for tx in 1..=200_000u32 {
+        let client = tx % 97;
+        match tx % 10 {
+            0 => writeln!(file, "dispute, {client}, {},", tx - 10),
+            1 => writeln!(file, "chargeback, {client}, {},", tx.saturating_sub(11)),
+            2 => writeln!(file, "withdrawal, {client}, {tx}, \"3.0\""),
+            _ => writeln!(file, "deposit, {client}, {tx}, 1.5"),
+        }
+        .unwrap();
+    }
+    drop(file);
+    // Larger than a segment, so the file is decoded by several workers.
+    assert!(input.as_file().metadata().unwrap().len() > 4 * 1024 * 1024);
+    let run = |parse_threads| {
+        let content = Arc::new(Mutex::new(vec![]));
+        let rejections = Arc::new(Mutex::new(vec![]));
+        let config = runtime::Config {
+            parse_threads,
+            rejections: Some(Box::new(TestWriter {
+                content: rejections.clone(),
+            })),
+            ..runtime::Config::new(1024)
+        };
+        let writer = TestWriter {
+            content: content.clone(),
+        };
+        runtime::run_file(input.path(), writer, config).unwrap();
+        let records = parse_records(&content.lock());
+        let rejections = rejections.lock().clone();
+        (format!("{records:?}"), rejections)
+    };
+
+    let expected = run(1);
+    assert!(!expected.1.is_empty());
+    assert_eq!(run(4), expected);
+}
+
+#[test]
+fn mmap_does_not_change_the_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let plain = dir.path().join("transactions.csv");
+    let empty = dir.path().join("empty.csv");
+    let compressed = dir.path().join("transactions.csv.gz");
+    std::fs::copy("tests/test.csv", &plain).unwrap();
+    std::fs::write(&empty, "").unwrap();
+    let mut encoder = Compression::Gzip
+        .encoder(std::fs::File::create(&compressed).unwrap())
+        .unwrap();
+    encoder
+        .write_all(&std::fs::read("tests/test.csv").unwrap())
+        .unwrap();
+    drop(encoder);
+
+    assert!(runtime::new_mapped_reader(&plain).unwrap().is_some());
+    assert!(runtime::new_mapped_reader(&compressed).unwrap().is_none());
+    assert!(runtime::new_mapped_reader(runtime::STDIO_PATH)
+        .unwrap()
+        .is_none());
+
+    let run = |path: &std::path::Path, mmap| {
+        let content = Arc::new(Mutex::new(vec![]));
+        let writer = TestWriter {
+            content: content.clone(),
+        };
+        let config = runtime::Config {
+            mmap,
+            ..runtime::Config::new(4)
+        };
+        runtime::run_file(path, writer, config).unwrap();
+        let records = parse_records(&content.lock());
+        format!("{records:?}")
+    };
+    let expected = run(&plain, false);
+    assert_eq!(run(&plain, true), expected);
+    assert_eq!(run(&compressed, true), expected);
+    assert_eq!(run(&empty, true), run(&empty, false));
+}
+
+#[test]
+fn run_with_pipeline_metrics() {
+    let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 1.5
+withdrawal, 2, 4, 1.0
+dispute, 2, 2,
+resolve, 2, 2,
+chargeback, 1, 1,
+";
+    let metrics = Metrics::new();
+    let config = runtime::Config {
+        batch_size: 2,
+        metrics: Some(metrics.clone()),
+        ..runtime::Config::new(4)
+    };
+    runtime::run_with(std::io::Cursor::new(input), std::io::sink(), config).unwrap();
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.reader.transactions, 7);
+    assert_eq!(snapshot.reader.batches, 4);
+    assert_eq!(snapshot.writer.transactions, 7);
+    assert_eq!(snapshot.channel.capacity, 2);
+    assert!(snapshot.channel.max_occupancy <= 2);
+    assert_eq!((snapshot.applied, snapshot.rejected), (5, 2));
+    assert_eq!(snapshot.latencies["deposit"].count, 2);
+    assert_eq!(snapshot.latencies["withdrawal"].count, 2);
+    assert_eq!(snapshot.latencies["chargeback"].count, 1);
+
+    let mut json = vec![];
+    snapshot.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["writer"]["transactions"], 7);
 }
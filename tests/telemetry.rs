@@ -0,0 +1,73 @@
+#![cfg(feature = "otel")]
+
+use payeng::prelude::runtime;
+use payeng::telemetry::Tracer;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Collector stand-in answering OTLP/HTTP requests, sending their path and body.
+fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line
+                .split(' ')
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            if tx.send((path, body)).is_err() {
+                break;
+            }
+        }
+    });
+    (endpoint, rx)
+}
+
+#[test]
+fn export_spans_over_otlp() {
+    let (endpoint, requests) = collector();
+    let guard = Tracer::new("payeng", "info")
+        .with_otlp_endpoint(&endpoint)
+        .init_subscriber(std::io::sink)
+        .unwrap();
+
+    let input = std::fs::File::open("tests/test.csv").unwrap();
+    runtime::run_with(input, std::io::sink(), runtime::Config::new(4)).unwrap();
+    drop(guard);
+
+    let (path, body) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(path, "/v1/traces");
+    for name in ["payeng", "Run all with config"] {
+        assert!(
+            body.windows(name.len()).any(|w| w == name.as_bytes()),
+            "missing `{name}` in the exported spans"
+        );
+    }
+}